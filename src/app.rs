@@ -1,14 +1,17 @@
 use std::{
+    collections::{HashMap, VecDeque},
     fmt::Debug,
     io::{Read, Stdout, Write},
     net::{IpAddr, TcpStream},
-    path::Path,
+    path::PathBuf,
+    rc::Rc,
     str::FromStr,
     sync::mpsc::{Receiver, Sender},
     thread,
+    time::Duration,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
 use crossterm::event::{KeyCode, KeyModifiers};
 use tui::{
@@ -19,41 +22,111 @@ use tui::{
 };
 
 use crate::{
+    config::Config,
+    known_peers::{KnownPeers, Trust},
     network::{
-        protocol::{File, Handshake, Message, Serializable},
+        cipher::{self, Keypair},
+        client::Client,
+        handshake::{self, PeerIdentity},
+        protocol::{
+            Capabilities, File, FileAccept, FileCancel, FileReject, FileResume, Handshake, Message,
+            Pong, Serializable,
+        },
+        transfer::{
+            self, CancelledTransfers, OutgoingDirectory, OutgoingTransfer, RateLimits, SaveTarget,
+            TransferDirection, TransferProgress,
+        },
         Server,
     },
     ui::{
         self,
         events::{Event, Events},
-        widgets::{self, DialogBox, DialogBoxType, DialogCallback, DialogState},
+        widgets::{
+            self, DialogBox, DialogBoxType, DialogCallback, DialogState, InputCallback,
+            TransferList,
+        },
     },
     ChannelMessage, DEFAULT_PORT,
 };
 
-type Client = TcpStream;
-
 /// The main data structure which contains all the necessary variables for `papercups`
 /// frontend
 #[derive(Debug)]
 pub struct App {
+    config: Config,
     client: Option<Client>,
+    offers: HashMap<u32, OutgoingTransfer>,
+    directory_offers: HashMap<u32, OutgoingDirectory>,
+    /// Accepted files waiting for the file being sent, which are sent one after another
+    send_queue: VecDeque<OutgoingTransfer>,
+    /// Transfer ID of the file from the queue which is being sent
+    active_send: Option<u32>,
+    /// Path and hash of the files being sent, by transfer ID
+    sending: HashMap<u32, (PathBuf, [u8; 32])>,
+    /// Path and hash of the files whose transfer was interrupted, which the peer can resume
+    interrupted: Vec<(PathBuf, [u8; 32])>,
+    /// Names of the files being received, by transfer ID
+    receiving: HashMap<u32, String>,
+    cancelled: CancelledTransfers,
+    limits: RateLimits,
     mode: AppMode,
+    /// Dialogs waiting for the open dialog to be closed
+    queued_dialogs: VecDeque<(AppMode, Option<DialogState>)>,
     state: State,
     rx: Receiver<ChannelMessage>,
     tx: Sender<ChannelMessage>,
+    /// Sender of the channel the UI thread receives from, handed to the threads sending files
+    ui_tx: Sender<ChannelMessage>,
     id: u32,
+    /// Key pair the peer identifies us by
+    keypair: Keypair,
+    known_peers: KnownPeers,
+    /// Who the peer proved to be on its connection to our server
+    server_peer: Option<PeerIdentity>,
+    /// Round trip time of the last ping the peer answered, shown in the status line
+    latency: Option<Duration>,
 }
 
 impl App {
-    pub fn new(rx: Receiver<ChannelMessage>, tx: Sender<ChannelMessage>) -> Self {
+    pub fn new(
+        config: Config,
+        keypair: Keypair,
+        known_peers: KnownPeers,
+        rx: Receiver<ChannelMessage>,
+        tx: Sender<ChannelMessage>,
+        ui_tx: Sender<ChannelMessage>,
+    ) -> Self {
+        // Peers can compare it with the fingerprint shown when they connect
+        let mut state = State::default();
+        let msg = format!(
+            "your fingerprint is {}",
+            cipher::fingerprint(keypair.public())
+        );
+        state.messages.push((MsgType::Sent, msg));
+
         Self {
+            limits: RateLimits::new(config.upload_limit),
+            config,
             client: None,
+            offers: HashMap::new(),
+            directory_offers: HashMap::new(),
+            send_queue: VecDeque::new(),
+            active_send: None,
+            sending: HashMap::new(),
+            interrupted: Vec::new(),
+            receiving: HashMap::new(),
+            cancelled: CancelledTransfers::default(),
             mode: AppMode::Standard,
-            state: State::default(),
+            queued_dialogs: VecDeque::new(),
+            state,
             id: crate::generate_id(),
+            keypair,
+            known_peers,
+            server_peer: None,
+            latency: None,
             rx,
             tx,
+            ui_tx,
         }
     }
 
@@ -61,10 +134,11 @@ impl App {
         thread::spawn(|| server.start_server());
         let mut term = ui::initialize_term()?;
 
-        self.start_ui_loop(&mut term)?;
+        // The terminal is restored even if the UI stops with an error, so the error can be read
+        let result = self.start_ui_loop(&mut term);
 
         ui::deinitialize_term(term)?;
-        Ok(())
+        result
     }
 
     fn start_ui_loop(&mut self, term: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
@@ -77,62 +151,1019 @@ impl App {
             if should_quit {
                 break Ok(());
             }
+            self.open_queued_dialog();
         }
     }
 
     fn recv_from_channel(&mut self) -> Result<()> {
-        for message in self.rx.try_iter() {
+        while let Ok(message) = self.rx.try_recv() {
             match message {
-                ChannelMessage::ConnectRequest(id, ip) => {
-                    if let None = self.client {
+                ChannelMessage::ConnectRequest(_, ip, key) => {
+                    if let (None, Some(refusal)) = (&key, self.plaintext_refusal(ip)) {
+                        self.tx.send(ChannelMessage::Disconnect(None))?;
+                        if self.client.is_some() {
+                            self.disconnected(None);
+                        }
+                        self.open_dialog(info_dialog_box(refusal));
+                    } else if self.client.is_none() {
+                        let fingerprint = key.as_ref().map(|peer| cipher::fingerprint(peer.key()));
+                        let identity = match &fingerprint {
+                            Some(fingerprint) => match self.known_peers.check(ip, fingerprint) {
+                                Trust::New => format!("Its fingerprint is {fingerprint}, which you have not accepted before."),
+                                Trust::Known => format!("Its fingerprint is {fingerprint}, which you accepted before."),
+                                Trust::Changed(known) => format!("WARNING: THE KEY OF THIS PEER HAS CHANGED!\n Its fingerprint was {known} and is now {fingerprint}.\n Someone may be impersonating the peer."),
+                            },
+                            None => "It does not support encryption, so it can not be identified.".to_string(),
+                        };
                         let msg = format!(
-                            "A connection request has been made by {ip} \nDo you want to accept?"
+                            "A connection request has been made by {ip} \n{identity}\nDo you want to accept?"
                         );
-                        (self.mode, self.state.dialog_state) = decision_dialog_box(
+                        self.open_dialog(decision_dialog_box(
                             msg,
                             Box::new(move |app| {
+                                if let Some(fingerprint) = &fingerprint {
+                                    app.trust_peer(ip, fingerprint);
+                                }
+                                app.server_peer = key.clone();
                                 app.tx.send(ChannelMessage::ConnectAccept)?;
-                                if let None = app.client {
-                                    if let Some(stream) = initiate_client(app.id, ip)? {
-                                        app.client = Some(stream);
-                                    } // TODO: Should log error when client sent an wrong handshake
+                                if app.client.is_none() {
+                                    app.connect(ip)?;
+                                    // The peer can not be talked to without both connections
+                                    if app.client.is_none() {
+                                        app.tx.send(ChannelMessage::Disconnect(None))?;
+                                    }
                                 }
                                 Ok(())
                             }),
                             Box::new(|app| {
-                                app.tx.send(ChannelMessage::Disconnect)?;
+                                app.tx.send(ChannelMessage::Disconnect(None))?;
                                 Ok(())
                             }),
-                        );
-                    } else {
+                        ));
+                    } else if self.is_connected_peer(ip, key.as_ref()) {
+                        self.server_peer = key;
                         self.tx.send(ChannelMessage::ConnectAccept)?;
+                        self.announce_verification();
+                    } else {
+                        self.tx.send(ChannelMessage::Disconnect(None))?;
+                        let msg = format!(
+                            "refused a connection from {ip}, which is not the connected peer"
+                        );
+                        self.state.messages.push((MsgType::Sent, msg));
                     }
                 }
+                ChannelMessage::ConnectFailed(ip, err) => {
+                    let msg = format!("rejected a connection from {ip}: {err}");
+                    self.state.messages.push((MsgType::Sent, msg));
+                }
                 ChannelMessage::Message(msg) => {
                     self.state.messages.push((MsgType::Recv, msg.message()))
                 }
                 ChannelMessage::File(file) => {
-                    let msg =
-                        "A file has been sent by the peer \nDo you want to save it?".to_string();
-                    (self.mode, self.state.dialog_state) = decision_dialog_box(
-                        msg,
+                    let description = format!("The peer has sent the file {}", file.name());
+                    let name = file.name().to_string();
+                    self.save_dialog(
+                        description,
+                        &name,
+                        move |app, target| {
+                            let path = match file.save(&target) {
+                                Ok(path) => path,
+                                Err(err) => {
+                                    let msg = format!("Unable to save the file.\n{err:#}");
+                                    app.open_dialog(info_dialog_box(msg));
+                                    return Ok(());
+                                }
+                            };
+                            app.state.messages.push((
+                                MsgType::Recv,
+                                format!(
+                                    "sent the file {} (saved to {})",
+                                    file.name(),
+                                    path.display()
+                                ),
+                            ));
+                            Ok(())
+                        },
+                        Box::new(|_| Ok(())),
+                    )?;
+                }
+                ChannelMessage::FileOffer(offer) => {
+                    let description = format!(
+                        "The peer wants to send the file {} ({} bytes)",
+                        offer.name(),
+                        offer.size()
+                    );
+                    let name = offer.name().to_string();
+                    let id = offer.id();
+                    self.save_dialog(
+                        description,
+                        &name,
+                        move |app, target| {
+                            if transfer::is_cancelled(&app.cancelled, id) {
+                                let msg = format!("cancelled {}", offer.name());
+                                app.state.messages.push((MsgType::Recv, msg));
+                                return Ok(());
+                            }
+                            app.receiving.insert(id, target.name());
+                            app.tx
+                                .send(ChannelMessage::AcceptFile(offer.clone(), target))?;
+                            if let Some(client) = &app.client {
+                                client.send(&FileAccept::new(id, app.compress()))?;
+                            }
+                            Ok(())
+                        },
                         Box::new(move |app| {
-                            file.save();
-                            Ok(app
-                                .state
-                                .messages
-                                .push((MsgType::Recv, "sent a file".to_string())))
+                            if let Some(client) = &app.client {
+                                client.send(&FileReject::new(id))?;
+                            }
+                            Ok(())
                         }),
-                        Box::new(|_| Ok(())),
+                    )?;
+                }
+                ChannelMessage::FileReady(transfer) => self.offer_file(transfer)?,
+                ChannelMessage::DirectoryReady(directory) => self.offer_directory(directory)?,
+                ChannelMessage::FileSentAsFrame(name) => {
+                    self.state
+                        .messages
+                        .push((MsgType::Sent, format!("sent the file {name}")));
+                }
+                ChannelMessage::SendFailed(msg) => {
+                    self.state.messages.push((MsgType::Sent, msg));
+                }
+                ChannelMessage::DirOffer(offer) => {
+                    let id = offer.id();
+                    let invalid_path = offer
+                        .entries()
+                        .iter()
+                        .find_map(|entry| transfer::sanitize_relative_path(entry.path()).err());
+                    if let Some(err) = invalid_path {
+                        if let Some(client) = &self.client {
+                            client.send(&FileReject::new(id))?;
+                        }
+                        let msg = format!("Rejected a directory sent by the peer.\n{err:#}");
+                        self.open_dialog(info_dialog_box(msg));
+                        continue;
+                    }
+
+                    let description = format!(
+                        "The peer wants to send the directory {} ({} files, {} bytes)",
+                        offer.name(),
+                        offer.files().count(),
+                        offer.size()
                     );
+                    let name = offer.name().to_string();
+                    self.save_dialog(
+                        description,
+                        &name,
+                        move |app, target| {
+                            if transfer::is_cancelled(&app.cancelled, id) {
+                                let msg = format!("cancelled {}", offer.name());
+                                app.state.messages.push((MsgType::Recv, msg));
+                                return Ok(());
+                            }
+                            for file in offer.files() {
+                                app.receiving.insert(file.id(), file.name().to_string());
+                            }
+                            app.tx
+                                .send(ChannelMessage::AcceptDir(offer.clone(), target))?;
+                            if let Some(client) = &app.client {
+                                client.send(&FileAccept::new(id, app.compress()))?;
+                            }
+                            Ok(())
+                        },
+                        Box::new(move |app| {
+                            if let Some(client) = &app.client {
+                                client.send(&FileReject::new(id))?;
+                            }
+                            Ok(())
+                        }),
+                    )?;
                 }
-                ChannelMessage::Disconnect => self.client = None,
+                ChannelMessage::FileAccepted(accept) => {
+                    let id = accept.id();
+                    let compress = accept.deflate() && self.compress();
+                    if let Some(transfer) = self.offers.remove(&id) {
+                        self.send_queue
+                            .push_back(transfer.with_compression(compress));
+                        self.send_next();
+                    } else if let (Some(client), Some(directory)) =
+                        (&self.client, self.directory_offers.remove(&id))
+                    {
+                        for (path, offer) in directory.files() {
+                            self.sending
+                                .insert(offer.id(), (path.clone(), offer.hash()));
+                        }
+                        let client = client.clone();
+                        let tx = self.ui_tx.clone();
+                        let cancelled = self.cancelled.clone();
+                        let limits = self.limits.clone();
+                        let directory = directory.with_compression(compress);
+                        thread::spawn(move || {
+                            transfer::send_directory(&client, directory, &tx, &cancelled, &limits)
+                        });
+                    }
+                }
+                ChannelMessage::FileRejected(id) => {
+                    if let Some(transfer) = self.offers.remove(&id) {
+                        self.state.remove_transfer(id);
+                        self.state.messages.push((
+                            MsgType::Recv,
+                            format!("rejected the file {}", transfer.name()),
+                        ));
+                    } else if let Some(directory) = self.directory_offers.remove(&id) {
+                        self.state.messages.push((
+                            MsgType::Recv,
+                            format!("rejected the directory {}", directory.name()),
+                        ));
+                    } else if let Some(transfer) = self.state.remove_transfer(id) {
+                        self.receiving.remove(&id);
+                        self.state.messages.push((
+                            MsgType::Recv,
+                            format!("can not resume the file {}", transfer.name()),
+                        ));
+                    }
+                }
+                ChannelMessage::ResumeRequested(resume) => {
+                    if let Some(client) = &self.client {
+                        let interrupted = self
+                            .interrupted
+                            .iter()
+                            .position(|(_, hash)| *hash == resume.hash());
+                        match interrupted {
+                            Some(index) => {
+                                let (path, hash) = self.interrupted.remove(index);
+                                self.sending.insert(resume.id(), (path.clone(), hash));
+                                let cancelled = self.cancelled.clone();
+                                let compress = resume.deflate() && self.compress();
+                                spawn_send_file(
+                                    client,
+                                    &self.ui_tx,
+                                    cancelled,
+                                    self.limits.clone(),
+                                    resume.id(),
+                                    move || {
+                                        OutgoingTransfer::resume(
+                                            &path,
+                                            resume.id(),
+                                            resume.hash(),
+                                            resume.offset(),
+                                        )
+                                        .map(|transfer| transfer.with_compression(compress))
+                                    },
+                                );
+                            }
+                            None => client.send(&FileReject::new(resume.id()))?,
+                        }
+                    }
+                }
+                ChannelMessage::FileSent(id) => {
+                    self.sending.remove(&id);
+                    self.limits.remove(id);
+                    if let Some(transfer) = self.state.remove_transfer(id) {
+                        self.state
+                            .messages
+                            .push((MsgType::Sent, format!("sent the file {}", transfer.name())));
+                    }
+                    self.finish_send(id);
+                }
+                ChannelMessage::FileCancelled(id) => {
+                    if let Some(name) = self.drop_transfer(id)? {
+                        let msg = format!("cancelled {name}");
+                        self.state.messages.push((MsgType::Recv, msg));
+                    }
+                }
+                ChannelMessage::FileReceived(file) => {
+                    self.receiving.remove(&file.id());
+                    self.state.remove_transfer(file.id());
+                    self.state.messages.push((
+                        MsgType::Recv,
+                        format!(
+                            "sent the file {} (saved to {})",
+                            file.name(),
+                            file.path().display()
+                        ),
+                    ));
+                }
+                ChannelMessage::TransferFailed(id, reason) => {
+                    // The peer stops its side of the transfer, unless the connection is gone
+                    if let Some(client) = &self.client {
+                        let _ = client.send(&FileCancel::new(id));
+                    }
+                    self.receiving.remove(&id);
+                    self.limits.remove(id);
+                    let sending = self.sending.remove(&id);
+                    let msg = match (self.state.remove_transfer(id), &sending) {
+                        (Some(transfer), _) if transfer.direction() == TransferDirection::Send => {
+                            format!("could not send the file {}: {reason}", transfer.name())
+                        }
+                        (Some(transfer), _) => {
+                            format!("could not receive the file {}: {reason}", transfer.name())
+                        }
+                        (None, Some((path, _))) => {
+                            format!("could not send the file {}: {reason}", path.display())
+                        }
+                        (None, None) => format!("could not receive a file: {reason}"),
+                    };
+                    self.state.messages.push((MsgType::Sent, msg));
+                    if let Some(sending) = sending {
+                        self.interrupted.push(sending);
+                    }
+                    self.finish_send(id);
+                }
+                ChannelMessage::Progress(progress) => self.state.update_transfer(progress),
+                ChannelMessage::ProtocolError(error) => {
+                    // The peer is told why, its connection is closed once the client is dropped
+                    if let Some(client) = &self.client {
+                        if client.capabilities().supports(Capabilities::ERRORS) {
+                            client.send(&error)?;
+                        }
+                    }
+                    let reason = format!(
+                        "The peer sent a {}: {}",
+                        error.code().description(),
+                        error.message()
+                    );
+                    self.disconnected(Some(reason));
+                }
+                ChannelMessage::Ping(ping) => {
+                    if let Some(client) = &self.client {
+                        client.send(&Pong::new(&ping))?;
+                    }
+                }
+                ChannelMessage::Latency(latency) => self.latency = Some(latency),
+                ChannelMessage::Disconnect(reason) => self.disconnected(reason),
                 _ => (),
             };
         }
         Ok(())
     }
 
+    /// Offers a file which was hashed on its own thread to the peer, unless the connection
+    /// was closed in the meantime
+    fn offer_file(&mut self, transfer: OutgoingTransfer) -> Result<()> {
+        if let Some(client) = &self.client {
+            client.send(&transfer.offer())?;
+            self.state.messages.push((
+                MsgType::Sent,
+                format!("offered the file {}", transfer.path().display()),
+            ));
+            let mut progress = transfer.progress();
+            progress.set_limit(self.limits.limit(transfer.id()));
+            self.state.update_transfer(progress);
+            self.offers.insert(transfer.id(), transfer);
+        }
+        Ok(())
+    }
+
+    /// Walks and hashes a directory tree on its own thread, which hands it to the UI thread to
+    /// be offered
+    fn send_directory(&self, path: &str) {
+        let path = PathBuf::from(path);
+        let tx = self.ui_tx.clone();
+        thread::spawn(move || {
+            let msg = match OutgoingDirectory::new(&path) {
+                Ok(directory) => ChannelMessage::DirectoryReady(directory),
+                Err(err) => ChannelMessage::SendFailed(format!(
+                    "could not send the directory {}: {err:#}",
+                    path.display()
+                )),
+            };
+            let _ = tx.send(msg);
+        });
+    }
+
+    /// Offers a directory tree which was hashed on its own thread to the peer, unless the
+    /// connection was closed in the meantime
+    fn offer_directory(&mut self, directory: OutgoingDirectory) -> Result<()> {
+        if let Some(client) = &self.client {
+            client.send(directory.offer())?;
+            self.state.messages.push((
+                MsgType::Sent,
+                format!("offered the directory {}", directory.name()),
+            ));
+            self.directory_offers.insert(directory.id(), directory);
+        }
+        Ok(())
+    }
+
+    /// Asks the user whether to save a file sent by the peer. If a file with the same name is
+    /// already in the download directory, the configured collision policy decides whether the
+    /// file is renamed, may overwrite it or is skipped without asking. The user can also choose
+    /// another path to save the file to
+    fn save_dialog<F>(
+        &mut self,
+        description: String,
+        name: &str,
+        accept: F,
+        reject: DialogCallback,
+    ) -> Result<()>
+    where
+        F: Fn(&mut App, SaveTarget) -> Result<()> + 'static,
+    {
+        let path = match transfer::download_path(&self.config.download_dir(), name) {
+            Ok(path) => path,
+            Err(err) => {
+                reject(self)?;
+                let msg = format!("Rejected a file sent by the peer.\n{err:#}");
+                self.open_dialog(info_dialog_box(msg));
+                return Ok(());
+            }
+        };
+
+        let target = match SaveTarget::for_collision(path, self.config.collision_policy) {
+            Some(target) => target,
+            None => {
+                reject(self)?;
+                self.state.messages.push((
+                    MsgType::Recv,
+                    format!("sent the file {name}, skipped as it already exists"),
+                ));
+                return Ok(());
+            }
+        };
+        let msg = if target.overwrites() {
+            format!("{description} \nA file with this name exists. \nDo you want to overwrite it?")
+        } else if target.path().exists() {
+            format!(
+                "{description} \nA file with this name exists, it will be saved as {}. \nDo you want to save it?",
+                transfer::unique_path(target.path()).file_name().unwrap_or_default().to_string_lossy()
+            )
+        } else {
+            format!("{description} \nDo you want to save it?")
+        };
+
+        let accept = Rc::new(accept);
+        let reject = Rc::new(reject);
+        let save_as_fn: InputCallback = {
+            let (accept, reject) = (accept.clone(), reject.clone());
+            let name = name.to_string();
+            Box::new(move |app: &mut App, input: &str| {
+                let path = match transfer::save_as_path(&app.config.download_dir(), input, &name) {
+                    Ok(path) => path,
+                    Err(err) => {
+                        reject(app)?;
+                        let msg = format!("Unable to save the file there.\n{err:#}");
+                        app.open_dialog(info_dialog_box(msg));
+                        return Ok(());
+                    }
+                };
+                if path.exists() {
+                    let msg = format!(
+                        "{} already exists. \nDo you want to overwrite it?",
+                        path.display()
+                    );
+                    let (accept, reject) = (accept.clone(), reject.clone());
+                    app.open_dialog(decision_dialog_box(
+                        msg,
+                        Box::new(move |app| accept(app, SaveTarget::new(path.clone(), true))),
+                        Box::new(move |app| reject(app)),
+                    ));
+                    Ok(())
+                } else {
+                    accept(app, SaveTarget::new(path, false))
+                }
+            })
+        };
+
+        let default_path = target.path().display().to_string();
+        let (mode, dialog_state) = decision_dialog_box(
+            msg,
+            Box::new(move |app| accept(app, target.clone())),
+            Box::new(move |app| reject(app)),
+        );
+        self.open_dialog((
+            mode,
+            dialog_state.map(|state| state.with_input(default_path, save_as_fn)),
+        ));
+        Ok(())
+    }
+
+    /// Offers every file matched by the given paths and globs to the peer as one batch, which
+    /// is shown in the transfer view and sent one file after another as the peer accepts them.
+    /// The files are read on their own thread, which hands each file to the UI thread to be
+    /// offered once it is hashed. Files which can not be sent are reported in the messages,
+    /// the others are still offered
+    fn send_files(&self, patterns: &[String], legacy: bool) {
+        let client = match &self.client {
+            Some(client) => client.clone(),
+            None => return,
+        };
+        // Peers which only speak the Tincan protocol can only receive single frames
+        let legacy = legacy || !client.capabilities().supports(Capabilities::STREAMING);
+        let max_size = self.config.max_frame_size.file;
+        let patterns = patterns.to_vec();
+        let tx = self.ui_tx.clone();
+
+        thread::spawn(move || {
+            for path in expand_paths(&patterns) {
+                let result = path.and_then(|path| {
+                    if legacy {
+                        let file = File::new(&path, max_size)?;
+                        client.send(&file)?;
+                        Ok(ChannelMessage::FileSentAsFrame(file.name().to_string()))
+                    } else {
+                        Ok(ChannelMessage::FileReady(OutgoingTransfer::new(&path)?))
+                    }
+                });
+                let msg = result.unwrap_or_else(|err| {
+                    ChannelMessage::SendFailed(format!("could not send a file: {err:#}"))
+                });
+                if tx.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Whether file chunks are compressed, which the peer has to support as well as the
+    /// configuration has to allow
+    fn compress(&self) -> bool {
+        let supported = self
+            .client
+            .as_ref()
+            .is_some_and(|client| client.capabilities().supports(Capabilities::COMPRESSION));
+        supported && self.config.compress()
+    }
+
+    /// Starts sending the next accepted file once the previous one has been sent
+    fn send_next(&mut self) {
+        if self.active_send.is_some() {
+            return;
+        }
+        if let (Some(client), Some(transfer)) = (&self.client, self.send_queue.pop_front()) {
+            let id = transfer.id();
+            self.sending
+                .insert(id, (transfer.path().to_path_buf(), transfer.hash()));
+            let cancelled = self.cancelled.clone();
+            let limits = self.limits.clone();
+            spawn_send_file(client, &self.ui_tx, cancelled, limits, id, move || {
+                Ok(transfer)
+            });
+            self.active_send = Some(id);
+        }
+    }
+
+    /// Moves on to the next queued file once the transfer of the active one has ended
+    fn finish_send(&mut self, id: u32) {
+        if self.active_send == Some(id) {
+            self.active_send = None;
+            self.send_next();
+        }
+    }
+
+    /// Cancels a transfer in either direction, or every transfer without an ID, and tells the
+    /// peer about it. A transfer which is not in progress but was interrupted loses its partial
+    /// file, so it can no longer be resumed
+    fn cancel_transfers(&mut self, id: Option<u32>) -> Result<()> {
+        let ids: Vec<u32> = match id {
+            Some(id) => vec![id],
+            None => {
+                let queued = self.send_queue.iter().map(|transfer| transfer.id());
+                let ids = self.offers.keys().chain(self.directory_offers.keys());
+                let ids = ids.chain(self.sending.keys()).chain(self.receiving.keys());
+                ids.copied().chain(queued).collect()
+            }
+        };
+        if ids.is_empty() {
+            let msg = "There are no transfers to cancel.";
+            self.open_dialog(info_dialog_box(msg.to_string()));
+        }
+
+        for id in ids {
+            match self.drop_transfer(id)? {
+                Some(name) => {
+                    if let Some(client) = &self.client {
+                        client.send(&FileCancel::new(id))?;
+                    }
+                    let msg = format!("cancelled {name}");
+                    self.state.messages.push((MsgType::Sent, msg));
+                }
+                None => self.discard_interrupted(id)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets a transfer cancelled by either side and returns its name, if it is in progress.
+    /// A file being sent stops streaming, and a file being received loses its partial file
+    fn drop_transfer(&mut self, id: u32) -> Result<Option<String>> {
+        // Offers which are still waiting for an answer are refused once they are accepted
+        if let Ok(mut cancelled) = self.cancelled.lock() {
+            cancelled.insert(id);
+        }
+        self.limits.remove(id);
+        self.state.remove_transfer(id);
+
+        let name = if let Some(transfer) = self.offers.remove(&id) {
+            Some(transfer.name().to_string())
+        } else if let Some(index) = self.send_queue.iter().position(|t| t.id() == id) {
+            self.send_queue
+                .remove(index)
+                .map(|transfer| transfer.name().to_string())
+        } else if let Some(directory) = self.directory_offers.remove(&id) {
+            Some(directory.name().to_string())
+        } else if let Some((path, _)) = self.sending.remove(&id) {
+            self.finish_send(id);
+            Some(
+                path.file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+            )
+        } else if let Some(name) = self.receiving.remove(&id) {
+            self.tx.send(ChannelMessage::CancelFile(id))?;
+            Some(name)
+        } else {
+            None
+        };
+        Ok(name)
+    }
+
+    /// Connects to the peer's server, showing why if the peer rejected the connection or the
+    /// handshake with it failed
+    fn connect(&mut self, ip: IpAddr) -> Result<()> {
+        match initiate_client(self.id, ip, &self.keypair) {
+            Ok(Some(client)) => self.connected(client),
+            Ok(None) => {
+                let msg = "Not able to connect successfully. \nThe peer rejected the connection or sent a wrong handshake.";
+                self.open_dialog(info_dialog_box(msg.to_string()));
+            }
+            Err(err) => {
+                let msg = format!("Not able to connect to {ip}.\n{err:#}");
+                self.open_dialog(info_dialog_box(msg));
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts using the connection to the peer's server, once the handshake succeeded. A peer
+    /// whose key was accepted before is refused if the connection is not encrypted
+    fn connected(&mut self, client: Client) {
+        if !client.capabilities().supports(Capabilities::STREAMING) {
+            let msg = "The peer only speaks the Tincan protocol, files are sent as single frames";
+            self.state.messages.push((MsgType::Sent, msg.to_string()));
+        }
+        if !client.capabilities().supports(Capabilities::ENCRYPTION) {
+            let msg = "The connection is not encrypted, anyone on the network can read it";
+            self.state.messages.push((MsgType::Sent, msg.to_string()));
+        }
+
+        let ip = client.peer_addr().ip();
+        let fingerprint = client.peer().map(|peer| cipher::fingerprint(peer.key()));
+        if let (None, Some(refusal)) = (&fingerprint, self.plaintext_refusal(ip)) {
+            client.close();
+            self.open_dialog(info_dialog_box(refusal));
+            return;
+        }
+        if let Some(fingerprint) = fingerprint {
+            match self.known_peers.check(ip, &fingerprint) {
+                Trust::New => self.trust_peer(ip, &fingerprint),
+                Trust::Known => (),
+                Trust::Changed(known) => {
+                    let msg = format!(
+                        "WARNING: THE KEY OF {ip} HAS CHANGED!\n Its fingerprint was {known} and is now {fingerprint}.\n Someone may be impersonating the peer.\n Do you trust the new key?"
+                    );
+                    self.open_dialog(decision_dialog_box(
+                        msg,
+                        Box::new(move |app| {
+                            app.trust_peer(ip, &fingerprint);
+                            Ok(())
+                        }),
+                        Box::new(|app| {
+                            app.tx.send(ChannelMessage::Disconnect(None))?;
+                            app.disconnected(None);
+                            Ok(())
+                        }),
+                    ));
+                }
+            }
+        }
+        self.client = Some(client);
+        self.latency = None;
+        self.announce_verification();
+    }
+
+    /// Why a connection without encryption is refused, if a key was accepted from the address
+    /// before. Whoever stripped the encryption of a peer which encrypted its connections before
+    /// may be impersonating it
+    fn plaintext_refusal(&self, ip: IpAddr) -> Option<String> {
+        self.known_peers.fingerprint(ip).map(|known| {
+            format!("WARNING: REFUSED A CONNECTION WITHOUT ENCRYPTION FROM {ip}!\n Its key with the fingerprint {known} was accepted before.\n Someone may be impersonating the peer.\n Remove it from known_peers to connect without encryption.")
+        })
+    }
+
+    /// Whether a peer connecting to our server is the peer our client is connected to, which
+    /// is accepted without asking the user again. It must connect from the same address, and
+    /// with the same trusted key if the connection of our client is encrypted
+    fn is_connected_peer(&self, ip: IpAddr, key: Option<&PeerIdentity>) -> bool {
+        let client = match &self.client {
+            Some(client) if client.peer_addr().ip() == ip => client,
+            _ => return false,
+        };
+        match (client.peer(), key) {
+            (None, None) => true,
+            (Some(client_peer), Some(server_peer)) => {
+                let fingerprint = cipher::fingerprint(server_peer.key());
+                client_peer.key() == server_peer.key()
+                    && self.known_peers.check(ip, &fingerprint) == Trust::Known
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the peer is who the user expects, as shown in the status line. Both connections
+    /// must be encrypted with the same key of the peer to be verified
+    fn verification(&self) -> Verification {
+        let client_peer = match self.client.as_ref().and_then(Client::peer) {
+            Some(peer) => peer,
+            None => return Verification::Unencrypted,
+        };
+        let server_peer = match &self.server_peer {
+            Some(peer) => peer,
+            None => return Verification::Pending,
+        };
+        if client_peer.key() != server_peer.key() {
+            return Verification::Mismatch;
+        }
+
+        let fingerprint = cipher::fingerprint(client_peer.key());
+        let ip = self.client.as_ref().map(|client| client.peer_addr().ip());
+        match ip {
+            Some(ip) if self.known_peers.is_verified(ip, &fingerprint) => Verification::Verified,
+            _ => Verification::Unverified(cipher::short_authentication_string([
+                client_peer.handshake_hash(),
+                server_peer.handshake_hash(),
+            ])),
+        }
+    }
+
+    /// Shows the short authentication string once both connections with the peer are set up
+    fn announce_verification(&mut self) {
+        let msg = match self.verification() {
+            Verification::Unverified(sas) => format!(
+                "the short authentication string is {sas}, if the peer sees the same one use ?verify"
+            ),
+            Verification::Verified => "the peer was verified before".to_string(),
+            Verification::Mismatch => {
+                "WARNING: the peer used different keys on both connections, someone may be impersonating it".to_string()
+            }
+            Verification::Unencrypted | Verification::Pending => return,
+        };
+        self.state.messages.push((MsgType::Sent, msg));
+    }
+
+    /// Marks the connected peer as verified, once the user compared the short authentication
+    /// string with the peer
+    fn verify_peer(&mut self) {
+        let (ip, key) = match (&self.client, self.verification()) {
+            (Some(client), Verification::Unverified(_) | Verification::Verified) => {
+                match client.peer() {
+                    Some(peer) => (client.peer_addr().ip(), peer.key().to_vec()),
+                    None => return,
+                }
+            }
+            (None, _) => {
+                let msg = "You are not connected to a peer.\n Connect to a peer using ?connect.";
+                self.open_dialog(info_dialog_box(msg.to_string()));
+                return;
+            }
+            (Some(_), Verification::Pending) => {
+                let msg = "The peer has not connected back yet.\n Wait for the short authentication string.";
+                self.open_dialog(info_dialog_box(msg.to_string()));
+                return;
+            }
+            (Some(_), Verification::Mismatch) => {
+                let msg =
+                    "The peer can not be verified.\n It used different keys on both connections.";
+                self.open_dialog(info_dialog_box(msg.to_string()));
+                return;
+            }
+            (Some(_), Verification::Unencrypted) => {
+                let msg = "The peer can not be verified.\n The connection is not encrypted.";
+                self.open_dialog(info_dialog_box(msg.to_string()));
+                return;
+            }
+        };
+
+        let fingerprint = cipher::fingerprint(&key);
+        let msg = match self.known_peers.verify(ip, &fingerprint) {
+            Ok(()) => format!("verified {ip} with the fingerprint {fingerprint}"),
+            Err(err) => format!("could not remember the verification of {ip}: {err:#}"),
+        };
+        self.state.messages.push((MsgType::Sent, msg));
+    }
+
+    /// Remembers the key of a peer, so a different key at its address is reported
+    fn trust_peer(&mut self, ip: IpAddr, fingerprint: &str) {
+        let msg = match self.known_peers.remember(ip, fingerprint) {
+            Ok(()) => format!("trusted the key of {ip} with the fingerprint {fingerprint}"),
+            Err(err) => format!("could not remember the key of {ip}: {err:#}"),
+        };
+        self.state.messages.push((MsgType::Sent, msg));
+    }
+
+    /// Changes the upload limit of a transfer, or the global one without an ID
+    fn set_limit(&mut self, limit: &LimitCommand) -> Result<()> {
+        let rate = match transfer::parse_rate(&limit.rate) {
+            Ok(rate) => rate,
+            Err(err) => {
+                self.open_dialog(info_dialog_box(format!("{err:#}")));
+                return Ok(());
+            }
+        };
+        let shown_rate = rate.map_or("no limit".to_string(), |_| format!("{}/s", limit.rate));
+
+        match limit.id.as_deref() {
+            None => {
+                self.limits.set_global(rate);
+                let msg = format!("limited uploads to {shown_rate}");
+                self.state.messages.push((MsgType::Sent, msg));
+            }
+            Some(id) => {
+                let id = u32::from_str_radix(id.trim_start_matches('#'), 16).ok();
+                let is_upload = id.filter(|id| {
+                    self.offers.contains_key(id)
+                        || self.sending.contains_key(id)
+                        || self.send_queue.iter().any(|transfer| transfer.id() == *id)
+                });
+                match is_upload {
+                    Some(id) => {
+                        self.limits.set_transfer(id, rate);
+                        let msg = format!("limited the upload {id:08x} to {shown_rate}");
+                        self.state.messages.push((MsgType::Sent, msg));
+                    }
+                    None => {
+                        let msg = "There is no upload with this ID.\n It is shown next to the name of the transfer.";
+                        self.open_dialog(info_dialog_box(msg.to_string()));
+                    }
+                }
+            }
+        }
+
+        for transfer in &mut self.state.transfers {
+            if transfer.direction() == TransferDirection::Send {
+                transfer.set_limit(self.limits.limit(transfer.id()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the partial file of an interrupted transfer
+    fn discard_interrupted(&mut self, id: u32) -> Result<()> {
+        let states = transfer::interrupted_transfers(&self.config.download_dir());
+        let result = states.and_then(|(states, errors)| {
+            self.report_skipped_states(errors);
+            let state = states.into_iter().find(|state| state.id() == id);
+            if let Some(state) = &state {
+                state.discard()?;
+            }
+            Ok(state)
+        });
+
+        match result {
+            Ok(Some(state)) => {
+                let msg = format!("discarded the interrupted file {}", state.name());
+                self.state.messages.push((MsgType::Sent, msg));
+            }
+            Ok(None) => {
+                let msg = format!("There is no transfer with the ID {id:08x}.");
+                self.open_dialog(info_dialog_box(msg));
+            }
+            Err(err) => {
+                let msg = format!("Unable to discard the interrupted transfer.\n{err:#}");
+                self.open_dialog(info_dialog_box(msg));
+            }
+        }
+        Ok(())
+    }
+
+    /// Shows a dialog, or queues it until the dialogs which are already open are closed, so
+    /// nothing asked by the peer is lost when several requests arrive at once
+    fn open_dialog(&mut self, (mode, dialog_state): (AppMode, Option<DialogState>)) {
+        if let AppMode::Standard = self.mode {
+            self.mode = mode;
+            self.state.dialog_state = dialog_state;
+        } else {
+            self.queued_dialogs.push_back((mode, dialog_state));
+        }
+    }
+
+    /// Shows the next queued dialog once the current one is closed
+    fn open_queued_dialog(&mut self) {
+        if let AppMode::Standard = self.mode {
+            if let Some(dialog) = self.queued_dialogs.pop_front() {
+                self.open_dialog(dialog);
+            }
+        }
+    }
+
+    /// Asks the peer to continue every interrupted transfer which is not already in progress
+    fn resume_transfers(&mut self) -> Result<()> {
+        let supported = self
+            .client
+            .as_ref()
+            .map(|client| client.capabilities().supports(Capabilities::STREAMING));
+        if supported == Some(false) {
+            let msg = "The peer can not resume transfers.\n It only speaks the Tincan protocol.";
+            self.open_dialog(info_dialog_box(msg.to_string()));
+            return Ok(());
+        }
+
+        let states = match transfer::interrupted_transfers(&self.config.download_dir()) {
+            Ok((states, errors)) => {
+                self.report_skipped_states(errors);
+                states
+            }
+            Err(err) => {
+                let msg = format!("Unable to find the interrupted transfers.\n{err:#}");
+                self.open_dialog(info_dialog_box(msg));
+                return Ok(());
+            }
+        };
+        // Only the files the connected peer was sending can be resumed
+        let sender = self
+            .client
+            .as_ref()
+            .map(|client| transfer::sender_id(client.peer_addr().ip(), client.peer()));
+        let states: Vec<_> = states
+            .into_iter()
+            .filter(|state| {
+                sender
+                    .as_deref()
+                    .is_some_and(|sender| state.is_from(sender))
+            })
+            .filter(|state| !self.state.transfers.iter().any(|t| t.id() == state.id()))
+            .collect();
+        if states.is_empty() {
+            let msg = "There are no interrupted transfers of the peer to resume.";
+            self.open_dialog(info_dialog_box(msg.to_string()));
+        }
+
+        if let Some(client) = &self.client {
+            for state in states {
+                let resume =
+                    FileResume::new(state.id(), state.offset(), state.hash(), self.compress());
+                let msg = format!(
+                    "asked to resume the file {} from byte {}",
+                    state.name(),
+                    state.offset()
+                );
+                // The server has to know about the transfer before the peer streams its chunks
+                self.receiving.insert(state.id(), state.name());
+                self.tx.send(ChannelMessage::ResumeFile(state))?;
+                client.send(&resume)?;
+                self.state.messages.push((MsgType::Sent, msg));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports the state files of interrupted transfers which could not be loaded, and were
+    /// skipped
+    fn report_skipped_states(&mut self, errors: Vec<anyhow::Error>) {
+        for err in errors {
+            self.state.messages.push((
+                MsgType::Sent,
+                format!("skipped an interrupted transfer: {err:#}"),
+            ));
+        }
+    }
+
+    /// Forgets the connection to the peer once either side closed it, showing the reason if it
+    /// was torn down because of the peer. The connection to the peer's server is closed as well,
+    /// which stops the threads sending files, and their transfers can be resumed later
+    fn disconnected(&mut self, reason: Option<String>) {
+        if let Some(reason) = reason {
+            let msg = format!("disconnected from the peer: {reason}");
+            self.state.messages.push((MsgType::Sent, msg));
+            let msg = format!("The connection to the peer was closed.\n {reason}");
+            self.open_dialog(info_dialog_box(msg));
+        }
+        if let Some(client) = self.client.take() {
+            client.close();
+        }
+        self.active_send = None;
+        self.server_peer = None;
+        self.latency = None;
+        self.receiving.clear();
+        for (id, _) in self.offers.drain() {
+            self.state.remove_transfer(id);
+        }
+        // The peer already expects the queued files, so they can be resumed
+        for transfer in self.send_queue.drain(..) {
+            self.state.remove_transfer(transfer.id());
+            self.interrupted
+                .push((transfer.path().to_path_buf(), transfer.hash()));
+        }
+        self.directory_offers.clear();
+        // Files of a directory which were still queued can be resumed like the interrupted one
+        let sending: Vec<_> = self.sending.drain().map(|(_, sending)| sending).collect();
+        self.interrupted.extend(sending);
+        self.state
+            .transfers
+            .retain(|transfer| transfer.direction() == TransferDirection::Send);
+    }
+
     fn draw_ui(&mut self, term: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
         term.draw(|f| {
             let chunks = Layout::default()
@@ -148,8 +1179,24 @@ impl App {
                 )
                 .split(f.size());
 
-            f.render_widget(widgets::connection_status_message(&self.client), chunks[0]);
-            f.render_widget(widgets::message_box(&self.state.messages), chunks[1]);
+            let verification = self.verification();
+            f.render_widget(
+                widgets::connection_status_message(&self.client, &verification, self.latency),
+                chunks[0],
+            );
+            if self.state.transfers.is_empty() {
+                f.render_widget(widgets::message_box(&self.state.messages), chunks[1]);
+            } else {
+                let message_chunks = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(65), Constraint::Percentage(35)].as_ref())
+                    .split(chunks[1]);
+                f.render_widget(
+                    widgets::message_box(&self.state.messages),
+                    message_chunks[0],
+                );
+                f.render_widget(TransferList::new(&self.state.transfers), message_chunks[1]);
+            }
             f.render_widget(widgets::input_box(&self.state.input), chunks[2]);
 
             if let AppMode::DialogBox(msg, d_type) = &self.mode {
@@ -158,7 +1205,7 @@ impl App {
                 f.render_stateful_widget(
                     DialogBox::new(msg.to_string(), *d_type),
                     centered_area,
-                    &mut self.state.dialog_state.as_mut().unwrap(),
+                    self.state.dialog_state.as_mut().unwrap(),
                 );
             }
         })?;
@@ -177,40 +1224,62 @@ impl App {
 
                         match Command::try_parse_from(splits) {
                             Ok(command) => match command.subcmd {
-                                Commands::Connect(c) => {
-                                    let ip = IpAddr::from_str(&c.ip)?;
-                                    if let Some(stream) = initiate_client(self.id, ip)? {
-                                        self.client = Some(stream)
-                                    } else {
-                                        let msg = "Not able to connect successfully. \nThe peer sent a wrong handshake.";
-                                        (self.mode, self.state.dialog_state) =
-                                            info_dialog_box(msg.to_string());
+                                Commands::Connect(c) => match IpAddr::from_str(&c.ip) {
+                                    Ok(ip) => self.connect(ip)?,
+                                    Err(err) => {
+                                        let msg =
+                                            format!("{} is not a valid IP address.\n{err}", c.ip);
+                                        self.open_dialog(info_dialog_box(msg));
                                     }
-                                }
+                                },
                                 Commands::Disconnect => {
-                                    if let Some(_) = self.client {
-                                        self.tx.send(ChannelMessage::Disconnect)?;
-                                        self.client = None;
+                                    if self.client.is_some() {
+                                        self.tx.send(ChannelMessage::Disconnect(None))?;
+                                        self.disconnected(None);
                                     }
                                 }
                                 Commands::File(file) => {
-                                    let path = Path::new(&file.path);
-                                    if let Some(client) = &self.client {
-                                        if let Some(file) = File::new(path) {
-                                            let mut client = client;
-                                            client.write(&file.to_bytes())?;
-                                            self.state
-                                                .messages
-                                                .push((MsgType::Sent, "sent a file".to_string()));
-                                        } else {
-                                            let msg = "The file is not present in the given path.";
-                                            (self.mode, self.state.dialog_state) =
-                                                info_dialog_box(msg.to_string());
+                                    if self.client.is_some() {
+                                        self.send_files(&file.paths, file.legacy);
+                                    } else {
+                                        let msg = "You are not connected to a peer.\n Connect to a peer using ?connect.";
+                                        self.open_dialog(info_dialog_box(msg.to_string()));
+                                    }
+                                }
+                                Commands::Dir(dir) => {
+                                    let supported = self.client.as_ref().map(|client| {
+                                        client.capabilities().supports(Capabilities::DIRECTORIES)
+                                    });
+                                    if supported == Some(false) {
+                                        let msg = "The peer can not receive directories.\n It only speaks the Tincan protocol.";
+                                        self.open_dialog(info_dialog_box(msg.to_string()));
+                                    } else if self.client.is_some() {
+                                        self.send_directory(&dir.path);
+                                    } else {
+                                        let msg = "You are not connected to a peer.\n Connect to a peer using ?connect.";
+                                        self.open_dialog(info_dialog_box(msg.to_string()));
+                                    }
+                                }
+                                Commands::Cancel(cancel) => {
+                                    let id = cancel.id.as_deref().map(|id| {
+                                        u32::from_str_radix(id.trim_start_matches('#'), 16)
+                                    });
+                                    match id.transpose() {
+                                        Ok(id) => self.cancel_transfers(id)?,
+                                        Err(_) => {
+                                            let msg = "The transfer ID is not valid.\n It is shown next to the name of the transfer.";
+                                            self.open_dialog(info_dialog_box(msg.to_string()));
                                         }
+                                    }
+                                }
+                                Commands::Limit(limit) => self.set_limit(&limit)?,
+                                Commands::Verify => self.verify_peer(),
+                                Commands::Resume => {
+                                    if self.client.is_some() {
+                                        self.resume_transfers()?;
                                     } else {
                                         let msg = "You are not connected to a peer.\n Connect to a peer using ?connect.";
-                                        (self.mode, self.state.dialog_state) =
-                                            info_dialog_box(msg.to_string());
+                                        self.open_dialog(info_dialog_box(msg.to_string()));
                                     }
                                 }
                                 Commands::Quit => {
@@ -219,26 +1288,32 @@ impl App {
                             },
                             Err(_) => {
                                 if let Some(client) = &self.client {
-                                    let mut client = client;
                                     let msg = Message::new(input);
-                                    client.write(&msg.to_bytes())?;
+                                    client.send(&msg)?;
                                     self.state.messages.push((MsgType::Sent, msg.message()));
                                 } else {
                                     let msg = "You are not connected to a peer.\n Connect to a peer using ?connect.";
-                                    (self.mode, self.state.dialog_state) =
-                                        info_dialog_box(msg.to_string());
+                                    self.open_dialog(info_dialog_box(msg.to_string()));
                                 }
                             }
                         }
                     }
+                    AppMode::DialogBox(_, DialogBoxType::Input) => {
+                        let answer = self.state.dialog_state.take().unwrap();
+                        self.mode = AppMode::Standard;
+                        if let Some(input_fn) = &answer.input_fn {
+                            input_fn(self, answer.input())?;
+                        }
+                    }
                     AppMode::DialogBox(..) => {
+                        // The mode is reset first, so the callbacks can open another dialog
                         let answer = self.state.dialog_state.take().unwrap();
+                        self.mode = AppMode::Standard;
                         if answer.is_yes() {
                             (answer.yes_fn)(self)?;
                         } else {
                             (answer.no_fn)(self)?;
                         }
-                        self.mode = AppMode::Standard;
                     }
                 },
 
@@ -258,15 +1333,37 @@ impl App {
                 KeyCode::Char(c) if c == 'c' && input.modifiers == KeyModifiers::CONTROL => {
                     return Ok(true);
                 }
-                KeyCode::Char(c) => {
-                    if let AppMode::Standard = self.mode {
-                        self.state.input.push(c);
+                KeyCode::Char(c) => match self.mode {
+                    AppMode::Standard => self.state.input.push(c),
+                    AppMode::DialogBox(_, DialogBoxType::Input) => {
+                        self.state.dialog_state.as_mut().unwrap().push(c)
                     }
-                }
-                KeyCode::Backspace => {
-                    if let AppMode::Standard = self.mode {
+                    AppMode::DialogBox(_, DialogBoxType::Decision)
+                        if c.eq_ignore_ascii_case(&'s')
+                            && self.state.dialog_state.as_ref().unwrap().has_input() =>
+                    {
+                        self.mode = AppMode::DialogBox(
+                            "Save the file as:".to_string(),
+                            DialogBoxType::Input,
+                        );
+                    }
+                    _ => (),
+                },
+                KeyCode::Backspace => match self.mode {
+                    AppMode::Standard => {
                         self.state.input.pop();
                     }
+                    AppMode::DialogBox(_, DialogBoxType::Input) => {
+                        self.state.dialog_state.as_mut().unwrap().pop()
+                    }
+                    _ => (),
+                },
+                KeyCode::Esc => {
+                    if let AppMode::DialogBox(_, DialogBoxType::Input) = self.mode {
+                        let answer = self.state.dialog_state.take().unwrap();
+                        self.mode = AppMode::Standard;
+                        (answer.no_fn)(self)?;
+                    }
                 }
                 _ => (),
             }
@@ -289,21 +1386,40 @@ pub enum MsgType {
     Sent,
 }
 
+/// Whether the connected peer is who the user expects
 #[derive(Debug)]
+pub enum Verification {
+    /// The connection is not encrypted, so the peer can not be identified
+    Unencrypted,
+    /// The peer has not connected to our server yet
+    Pending,
+    /// The peer used different keys on the two connections
+    Mismatch,
+    /// The user has not compared the short authentication string with the peer yet
+    Unverified(String),
+    Verified,
+}
+
+#[derive(Debug, Default)]
 struct State {
     messages: Vec<(MsgType, String)>,
+    transfers: Vec<TransferProgress>,
     input: String,
     dialog_state: Option<DialogState>,
 }
 
-impl Default for State {
-    fn default() -> Self {
-        Self {
-            messages: Vec::new(),
-            input: String::new(),
-            dialog_state: None,
+impl State {
+    fn update_transfer(&mut self, progress: TransferProgress) {
+        match self.transfers.iter_mut().find(|t| t.id() == progress.id()) {
+            Some(transfer) => *transfer = progress,
+            None => self.transfers.push(progress),
         }
     }
+
+    fn remove_transfer(&mut self, id: u32) -> Option<TransferProgress> {
+        let index = self.transfers.iter().position(|t| t.id() == id)?;
+        Some(self.transfers.remove(index))
+    }
 }
 
 #[derive(Debug, Parser)]
@@ -317,6 +1433,11 @@ enum Commands {
     Connect(ConnectCommand),
     Disconnect,
     File(FileCommnad),
+    Dir(DirCommand),
+    Cancel(CancelCommand),
+    Limit(LimitCommand),
+    Resume,
+    Verify,
     Quit,
 }
 
@@ -327,25 +1448,108 @@ struct ConnectCommand {
 
 #[derive(Debug, Parser)]
 struct FileCommnad {
+    /// Sends the files as single Tincan compatible frames instead of streaming them in chunks
+    #[clap(short, long)]
+    legacy: bool,
+    /// Paths of the files, which can be shell-style globs like `logs/*.txt`
+    #[clap(required = true)]
+    paths: Vec<String>,
+}
+
+#[derive(Debug, Parser)]
+struct DirCommand {
     path: String,
 }
 
-fn initiate_client(id: u32, ip: IpAddr) -> Result<Option<TcpStream>> {
+#[derive(Debug, Parser)]
+struct CancelCommand {
+    /// ID of the transfer shown in the transfer view, every transfer is cancelled without it
+    id: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+struct LimitCommand {
+    /// Upload rate like `500K` or `2M`, `off` removes the limit
+    rate: String,
+    /// ID of the transfer shown in the transfer view, the limit applies to every upload without it
+    id: Option<String>,
+}
+
+/// Expands the shell-style globs among the paths given to a command, in order. Paths without
+/// glob characters are kept as they are, so a missing file is reported when it is opened
+fn expand_paths(patterns: &[String]) -> Vec<Result<PathBuf>> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            paths.push(Ok(PathBuf::from(pattern)));
+            continue;
+        }
+
+        let matches = match glob::glob(pattern) {
+            Ok(matches) => matches,
+            Err(err) => {
+                paths.push(Err(anyhow!("{} is not a valid pattern: {}", pattern, err)));
+                continue;
+            }
+        };
+        let count = paths.len();
+        for path in matches {
+            paths.push(path.map_err(|err| anyhow!("Unable to read {}", err.path().display())));
+        }
+        if paths.len() == count {
+            paths.push(Err(anyhow!("No file matches {}", pattern)));
+        }
+    }
+    paths
+}
+
+fn initiate_client(id: u32, ip: IpAddr, keypair: &Keypair) -> Result<Option<Client>> {
     let mut stream = TcpStream::connect((ip, DEFAULT_PORT))?;
 
-    let handshake = Handshake::new(id);
-    stream.write(&handshake.to_bytes())?;
+    let handshake = Handshake::papercups(id);
+    stream.write_all(&handshake.to_bytes())?;
 
-    let mut buf = [0u8; 9];
+    let mut buf = [0u8; Handshake::LENGTH];
     stream.read_exact(&mut buf)?;
     let recv_handshake =
         Handshake::from_bytes(buf.to_vec()).context("Malformed Handshake message")?;
 
-    if recv_handshake == handshake {
-        Ok(Some(stream))
-    } else {
-        Ok(None)
+    if recv_handshake != handshake {
+        return Ok(None);
     }
+    let session = handshake::connect_session(&mut stream, keypair)?;
+
+    // Papercups peers repeat the handshake once their user accepted the connection
+    if session.confirms_connection() {
+        stream.read_exact(&mut buf)?;
+        let decision =
+            Handshake::from_bytes(buf.to_vec()).context("Malformed Handshake message")?;
+        if decision != handshake {
+            return Ok(None);
+        }
+    }
+    Ok(Some(Client::new(stream, session)?))
+}
+
+/// Sends the file opened by `open` on its own thread, reporting a failure to the UI thread
+fn spawn_send_file<F>(
+    client: &Client,
+    tx: &Sender<ChannelMessage>,
+    cancelled: CancelledTransfers,
+    limits: RateLimits,
+    id: u32,
+    open: F,
+) where
+    F: FnOnce() -> Result<OutgoingTransfer> + Send + 'static,
+{
+    let client = client.clone();
+    let tx = tx.clone();
+    thread::spawn(move || {
+        let send = |transfer| transfer::send_file(&client, transfer, &tx, &cancelled, &limits);
+        if let Err(err) = open().and_then(send) {
+            let _ = tx.send(ChannelMessage::TransferFailed(id, format!("{err:#}")));
+        }
+    });
 }
 
 fn decision_dialog_box(
@@ -365,3 +1569,62 @@ fn info_dialog_box(msg: String) -> (AppMode, Option<DialogState>) {
         Some(DialogState::default()),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use clap::Parser;
+
+    use super::{expand_paths, Command, Commands};
+    use crate::network::testing::temp_dir;
+
+    #[test]
+    fn parses_several_paths_per_file_command() {
+        let command = Command::try_parse_from(["?", "file", "--legacy", "a.txt", "*.log"]).unwrap();
+        match command.subcmd {
+            Commands::File(file) => {
+                assert!(file.legacy);
+                assert_eq!(file.paths, ["a.txt", "*.log"]);
+            }
+            command => panic!("parsed as {command:?}"),
+        }
+        assert!(Command::try_parse_from(["?", "file"]).is_err());
+    }
+
+    #[test]
+    fn expands_globs_in_order() {
+        let dir = temp_dir();
+        for name in ["b.log", "a.log", "notes.txt"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let patterns = [
+            dir.join("notes.txt"),
+            dir.join("*.log"),
+            dir.join("missing.txt"),
+            dir.join("*.pdf"),
+            dir.join("[.txt"),
+        ]
+        .map(|pattern| pattern.to_string_lossy().to_string());
+
+        let paths = expand_paths(&patterns);
+        let found: Vec<PathBuf> = paths[..4]
+            .iter()
+            .map(|path| path.as_ref().unwrap().clone())
+            .collect();
+        assert_eq!(
+            found,
+            [
+                dir.join("notes.txt"),
+                dir.join("a.log"),
+                dir.join("b.log"),
+                dir.join("missing.txt")
+            ]
+        );
+        // A pattern which matches nothing or is not valid is reported
+        assert_eq!(paths.len(), 6);
+        assert!(paths[4].is_err());
+        assert!(paths[5].is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    env, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::{de, Deserialize, Deserializer};
+
+use crate::network::{client::PING_INTERVAL, transfer};
+
+/// Name of the configuration file inside the `papercups` configuration directory
+const CONFIG_FILE: &str = "config.toml";
+
+/// Seconds a peer which sends pings may stay silent before it is disconnected, by default
+const DEFAULT_IDLE_TIMEOUT: u64 = 30;
+
+/// Settings of `papercups`, read from `config.toml` in the configuration directory.
+/// Every setting is optional and falls back to its default
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    /// Directory received files are saved to, instead of `papercups` in the download directory
+    pub download_dir: Option<PathBuf>,
+    /// What to do when a received file has the name of a file which already exists
+    pub collision_policy: CollisionPolicy,
+    /// Rate in bytes per second all outgoing file data together is limited to, written like
+    /// `500K` or `2M` in the file
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub upload_limit: Option<u64>,
+    /// Whether file data is compressed when the peer supports it
+    pub compression: Compression,
+    /// Largest frame of each type accepted from the peer
+    pub max_frame_size: MaxFrameSizes,
+    /// Seconds a peer which sends pings may stay silent before it is considered gone
+    pub idle_timeout: Option<u64>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let path = match config_dir() {
+            Some(dir) => dir.join(CONFIG_FILE),
+            None => return Ok(Self::default()),
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .with_context(|| format!("{} is not a valid configuration file", path.display())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("Unable to read {}", path.display())),
+        }
+    }
+
+    /// Directory received files are saved to. Without a configured one, files are saved to
+    /// `papercups` in the download directory of the platform, in `~/Downloads` if the platform
+    /// has none (which is common on headless servers), or in the current directory
+    pub fn download_dir(&self) -> PathBuf {
+        if let Some(dir) = &self.download_dir {
+            return expand_home(dir);
+        }
+        dirs::download_dir()
+            .or_else(|| dirs::home_dir().map(|home| home.join("Downloads")))
+            .or_else(|| env::current_dir().ok())
+            .unwrap_or_default()
+            .join(env!("CARGO_PKG_NAME"))
+    }
+
+    /// How long a peer which sends pings may stay silent before it is disconnected. It is at
+    /// least twice the ping interval, so a single late ping does not drop the connection
+    pub fn idle_timeout(&self) -> Duration {
+        let timeout = Duration::from_secs(self.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT));
+        timeout.max(2 * PING_INTERVAL)
+    }
+
+    pub fn compress(&self) -> bool {
+        self.compression == Compression::Deflate
+    }
+}
+
+/// Directory `papercups` keeps its configuration in
+pub fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(env!("CARGO_PKG_NAME")))
+}
+
+/// Replaces a leading `~` of a path with the home directory
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Decides how a received file is saved when a file with the same name already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionPolicy {
+    /// Saves the file as `name (1).ext`, `name (2).ext`, ... whichever does not exist yet
+    #[default]
+    Rename,
+    /// Asks the user whether the existing file should be overwritten
+    Prompt,
+    /// Rejects the file
+    Skip,
+}
+
+/// Compression of the data of the files which are streamed to or from the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    /// Compresses the files which are not already compressed with deflate, when both peers
+    /// support it
+    #[default]
+    Deflate,
+    /// Sends the files as they are
+    Off,
+}
+
+/// Largest frame of each type accepted from the peer, header included, written like `64K` or
+/// `16M` in the file. A peer sending a larger frame is disconnected before the frame is read,
+/// so a bogus length can not make us allocate gigabytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct MaxFrameSizes {
+    /// Chat messages
+    #[serde(deserialize_with = "deserialize_size")]
+    pub message: u32,
+    /// Files sent as single frames by Tincan, or with `?file --legacy`
+    #[serde(deserialize_with = "deserialize_size")]
+    pub file: u32,
+    /// Chunks of streamed files
+    #[serde(deserialize_with = "deserialize_size")]
+    pub file_chunk: u32,
+    /// Offers of single files
+    #[serde(deserialize_with = "deserialize_size")]
+    pub file_offer: u32,
+    /// Offers of directories, which list every entry of the tree
+    #[serde(deserialize_with = "deserialize_size")]
+    pub dir_offer: u32,
+    /// Acceptances, rejections, cancellations and resume requests of transfers, errors, and
+    /// frames of unknown types
+    #[serde(deserialize_with = "deserialize_size")]
+    pub control: u32,
+}
+
+impl MaxFrameSizes {
+    /// Largest frame accepted for the type in the header of a frame
+    pub fn for_type(&self, msg_type: &[u8]) -> u32 {
+        match msg_type {
+            b"chat" => self.message,
+            b"file" => self.file,
+            b"fchk" | b"fchz" => self.file_chunk,
+            b"offr" => self.file_offer,
+            b"dofr" => self.dir_offer,
+            _ => self.control,
+        }
+    }
+}
+
+impl Default for MaxFrameSizes {
+    fn default() -> Self {
+        Self {
+            message: 1 << 20,
+            file: 64 << 20,
+            file_chunk: 1 << 20,
+            file_offer: 64 << 10,
+            dir_offer: 16 << 20,
+            control: 4 << 10,
+        }
+    }
+}
+
+/// Reads a size like `64K` from the configuration file
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let size = String::deserialize(deserializer)?;
+    let bytes = transfer::parse_size(&size).map_err(de::Error::custom)?;
+    u32::try_from(bytes).map_err(|_| de::Error::custom(format!("{size} is larger than 4G")))
+}
+
+/// Reads a rate like `500K` from the configuration file, `off` means no limit
+fn deserialize_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    let rate = String::deserialize(deserializer)?;
+    transfer::parse_rate(&rate).map_err(de::Error::custom)
+}
@@ -0,0 +1,225 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::config;
+
+/// Name of the file the fingerprints of the known peers are kept in, inside the `papercups`
+/// configuration directory
+const KNOWN_PEERS_FILE: &str = "known_peers";
+
+/// Fingerprints of the keys of the peers which were accepted before, by address. A peer is
+/// trusted on first use, and a different key at the same address afterwards is reported
+#[derive(Debug, Default)]
+pub struct KnownPeers {
+    /// File the fingerprints are saved to, `None` without a configuration directory
+    path: Option<PathBuf>,
+    peers: HashMap<IpAddr, KnownPeer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct KnownPeer {
+    fingerprint: String,
+    /// Whether the user compared the short authentication string with the peer
+    verified: bool,
+}
+
+/// What is known about the key a peer presented
+#[derive(Debug, PartialEq, Eq)]
+pub enum Trust {
+    /// No key was accepted from the address before
+    New,
+    /// The key is the one which was accepted before
+    Known,
+    /// A different key was accepted from the address before, which has this fingerprint
+    Changed(String),
+}
+
+impl KnownPeers {
+    pub fn load() -> Result<Self> {
+        let path = match config::config_dir() {
+            Some(dir) => dir.join(KNOWN_PEERS_FILE),
+            None => return Ok(Self::default()),
+        };
+        let peers = match fs::read_to_string(&path) {
+            Ok(contents) => parse(&path, &contents)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Unable to read {}", path.display()))
+            }
+        };
+        Ok(Self {
+            path: Some(path),
+            peers,
+        })
+    }
+
+    pub fn check(&self, ip: IpAddr, fingerprint: &str) -> Trust {
+        match self.peers.get(&ip) {
+            None => Trust::New,
+            Some(known) if known.fingerprint == fingerprint => Trust::Known,
+            Some(known) => Trust::Changed(known.fingerprint.clone()),
+        }
+    }
+
+    /// Fingerprint of the key which was accepted from the address before
+    pub fn fingerprint(&self, ip: IpAddr) -> Option<&str> {
+        self.peers.get(&ip).map(|known| known.fingerprint.as_str())
+    }
+
+    /// Whether the key with the fingerprint was verified for the address
+    pub fn is_verified(&self, ip: IpAddr, fingerprint: &str) -> bool {
+        self.peers
+            .get(&ip)
+            .is_some_and(|known| known.fingerprint == fingerprint && known.verified)
+    }
+
+    /// Trusts the key with the fingerprint for the address from now on, replacing the key which
+    /// was trusted before. A replaced key loses its verification
+    pub fn remember(&mut self, ip: IpAddr, fingerprint: &str) -> Result<()> {
+        if self.check(ip, fingerprint) == Trust::Known {
+            return Ok(());
+        }
+        let peer = KnownPeer {
+            fingerprint: fingerprint.to_string(),
+            verified: false,
+        };
+        self.peers.insert(ip, peer);
+        self.save()
+    }
+
+    /// Marks the key with the fingerprint as verified for the address, trusting it if it was not
+    pub fn verify(&mut self, ip: IpAddr, fingerprint: &str) -> Result<()> {
+        let peer = KnownPeer {
+            fingerprint: fingerprint.to_string(),
+            verified: true,
+        };
+        self.peers.insert(ip, peer);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Unable to create {}", dir.display()))?;
+        }
+
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort();
+        let contents: String = peers
+            .into_iter()
+            .map(|(ip, peer)| match peer.verified {
+                true => format!("{ip} {} verified\n", peer.fingerprint),
+                false => format!("{ip} {}\n", peer.fingerprint),
+            })
+            .collect();
+        fs::write(path, contents).with_context(|| format!("Unable to write {}", path.display()))
+    }
+}
+
+/// Parses the lines of the known peers file, an address, a fingerprint and `verified` for the
+/// verified peers, separated by spaces
+fn parse(path: &Path, contents: &str) -> Result<HashMap<IpAddr, KnownPeer>> {
+    let mut peers = HashMap::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let invalid = || anyhow!("{} has an invalid line: {}", path.display(), line);
+        let mut fields = line.split_whitespace();
+        let ip = fields
+            .next()
+            .and_then(|ip| ip.parse().ok())
+            .ok_or_else(invalid)?;
+        let fingerprint = fields.next().ok_or_else(invalid)?.to_string();
+        let verified = match fields.next() {
+            None => false,
+            Some("verified") => true,
+            Some(_) => return Err(invalid()),
+        };
+        peers.insert(
+            ip,
+            KnownPeer {
+                fingerprint,
+                verified,
+            },
+        );
+    }
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::IpAddr, path::Path};
+
+    use super::{parse, KnownPeers, Trust};
+    use crate::network::testing::temp_dir;
+
+    const ALICE: &str = "0a1b:2c3d:4e5f:6071:8293:a4b5:c6d7:e8f9";
+    const MALLORY: &str = "ffff:eeee:dddd:cccc:bbbb:aaaa:9999:8888";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_known_peers() {
+        let contents = format!("192.0.2.1 {ALICE}\n\n::1 {MALLORY} verified\n");
+        let peers = parse(Path::new("known_peers"), &contents).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert!(!peers[&ip("192.0.2.1")].verified);
+        assert_eq!(peers[&ip("::1")].fingerprint, MALLORY);
+        assert!(peers[&ip("::1")].verified);
+
+        let invalid = [
+            "192.0.2.1".to_string(),
+            format!("nowhere {ALICE}"),
+            format!("192.0.2.1 {ALICE} trusted"),
+        ];
+        for line in invalid {
+            assert!(
+                parse(Path::new("known_peers"), &line).is_err(),
+                "{line:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn trusts_keys_on_first_use() {
+        let dir = temp_dir();
+        let path = dir.join("known_peers");
+        let mut peers = KnownPeers {
+            path: Some(path.clone()),
+            ..KnownPeers::default()
+        };
+        let peer = ip("192.0.2.1");
+        assert_eq!(peers.check(peer, ALICE), Trust::New);
+
+        peers.remember(peer, ALICE).unwrap();
+        assert_eq!(peers.check(peer, ALICE), Trust::Known);
+        assert_eq!(
+            peers.check(peer, MALLORY),
+            Trust::Changed(ALICE.to_string())
+        );
+        assert_eq!(peers.check(ip("192.0.2.2"), ALICE), Trust::New);
+        assert!(!peers.is_verified(peer, ALICE));
+
+        peers.verify(peer, ALICE).unwrap();
+        assert!(peers.is_verified(peer, ALICE));
+        let saved = parse(&path, &fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved, peers.peers);
+
+        // A replaced key loses its verification
+        peers.remember(peer, MALLORY).unwrap();
+        assert_eq!(peers.fingerprint(peer), Some(MALLORY));
+        assert!(!peers.is_verified(peer, MALLORY));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,15 +1,25 @@
 mod app;
+mod config;
+mod known_peers;
 mod network;
 mod ui;
 
-use std::{net::IpAddr, sync::mpsc};
+use std::{net::IpAddr, sync::mpsc, time::Duration};
 
 use anyhow::Result;
 use rand::Rng;
 
 use crate::app::App;
+use crate::config::Config;
+use crate::known_peers::KnownPeers;
 use crate::network::{
-    protocol::{File, Message},
+    cipher::Keypair,
+    handshake::PeerIdentity,
+    protocol::{DirOffer, File, FileAccept, FileOffer, FileResume, Message, Ping, ProtocolError},
+    transfer::{
+        OutgoingDirectory, OutgoingTransfer, ReceivedFile, ResumeState, SaveTarget,
+        TransferProgress,
+    },
     Server,
 };
 
@@ -17,11 +27,44 @@ pub const DEFAULT_PORT: u16 = 42069;
 
 /// Payload used in channels between UI thread and server thread
 pub enum ChannelMessage {
-    ConnectRequest(u32, IpAddr),
+    /// A peer asks to connect, with who it proved to be if the session is encrypted
+    ConnectRequest(u32, IpAddr, Option<PeerIdentity>),
     ConnectAccept,
+    /// A peer connected but failed the handshake, with the reason
+    ConnectFailed(IpAddr, String),
     Message(Message),
     File(File),
-    Disconnect,
+    FileOffer(FileOffer),
+    /// A file was hashed on its own thread and can be offered to the peer
+    FileReady(OutgoingTransfer),
+    /// A directory tree was walked and hashed on its own thread and can be offered to the peer
+    DirectoryReady(OutgoingDirectory),
+    /// A file was sent as a single frame on its own thread, with its name
+    FileSentAsFrame(String),
+    /// A file or directory could not be offered or sent on its own thread, with the reason
+    SendFailed(String),
+    AcceptFile(FileOffer, SaveTarget),
+    DirOffer(DirOffer),
+    AcceptDir(DirOffer, SaveTarget),
+    FileAccepted(FileAccept),
+    FileRejected(u32),
+    ResumeFile(ResumeState),
+    ResumeRequested(FileResume),
+    CancelFile(u32),
+    FileCancelled(u32),
+    FileSent(u32),
+    FileReceived(ReceivedFile),
+    TransferFailed(u32, String),
+    Progress(TransferProgress),
+    /// The peer sent a frame which broke the protocol, and its connection was torn down
+    ProtocolError(ProtocolError),
+    /// The peer pinged our server, the UI thread answers on its connection to the peer
+    Ping(Ping),
+    /// Round trip time of our last ping which the peer answered
+    Latency(Duration),
+    /// Disconnects from the peer, with the reason if the connection was torn down because of
+    /// the peer
+    Disconnect(Option<String>),
 }
 
 pub fn generate_id() -> u32 {
@@ -35,9 +78,18 @@ pub fn generate_id() -> u32 {
 }
 
 pub fn start_papercups() -> Result<()> {
+    let config = Config::load()?;
     let (atx, srx) = mpsc::channel();
     let (stx, arx) = mpsc::channel();
-    let app = App::new(arx, atx);
-    let server = Server::new(srx, stx);
+    let keypair = Keypair::load()?;
+    let server = Server::new(
+        config.download_dir(),
+        keypair.clone(),
+        config.max_frame_size,
+        config.idle_timeout(),
+        srx,
+        stx.clone(),
+    );
+    let app = App::new(config, keypair, KnownPeers::load()?, arx, atx, stx);
     app.start(server)
 }
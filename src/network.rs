@@ -1,35 +1,90 @@
 use std::{
-    io::{Read, Write},
-    net::{Ipv4Addr, Shutdown, TcpListener, TcpStream},
-    sync::mpsc::{Receiver, Sender},
+    collections::HashMap,
+    io::{self, ErrorKind, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender},
+        Arc,
+    },
+    thread,
     time::Duration,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Error, Result};
 
 use crate::{
+    config::MaxFrameSizes,
     network::protocol::{ProtocolMessage, Serializable},
     ChannelMessage, DEFAULT_PORT,
 };
 
-use self::protocol::Handshake;
+use self::{
+    cipher::{FrameReader, Keypair},
+    handshake::Session,
+    protocol::{Capabilities, ErrorCode, FileOffer, Handshake, ProtocolError},
+    transfer::{IncomingTransfer, SaveTarget},
+};
 
+pub mod cipher;
+pub mod client;
+pub mod handshake;
 pub mod protocol;
+pub mod transfer;
+
+/// Helpers shared by the tests of the network and of the modules which use it
+#[cfg(test)]
+pub mod testing;
+
+/// How often the server checks whether a peer which sends pings went silent, and handles the
+/// messages of the UI thread while the peer sends nothing
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many connecting peers can be in their handshake at once, further peers are turned away
+/// until one of them is done. Peers whose handshake is done wait in a queue of the same size
+const MAX_HANDSHAKES: usize = 8;
+
+/// Peer which connected to the server, with its stream, handshake and session if it got through
+/// the handshake
+type Greeting = (SocketAddr, Result<(TcpStream, Handshake, Option<Session>)>);
 
 /// Strcuture containing the state of `papercups` backend or server stack
 #[derive(Debug)]
 pub struct Server {
-    server: TcpListener,
-    peer_stream: Option<TcpStream>,
+    /// Peers which connected, as they get through their handshake
+    greetings: Receiver<Greeting>,
+    peer_stream: Option<FrameReader>,
+    transfers: HashMap<u32, IncomingTransfer>,
+    /// Who the connected peer is, recorded with its transfers so they are only resumed with it
+    sender: String,
+    /// Directory the partial files of incoming transfers are written to
+    download_dir: PathBuf,
+    max_frame_sizes: MaxFrameSizes,
+    /// How long a peer which sends pings may stay silent before it is disconnected
+    idle_timeout: Duration,
     rx: Receiver<ChannelMessage>,
     tx: Sender<ChannelMessage>,
 }
 
 impl Server {
-    pub fn new(rx: Receiver<ChannelMessage>, tx: Sender<ChannelMessage>) -> Self {
+    pub fn new(
+        download_dir: PathBuf,
+        keypair: Keypair,
+        max_frame_sizes: MaxFrameSizes,
+        idle_timeout: Duration,
+        rx: Receiver<ChannelMessage>,
+        tx: Sender<ChannelMessage>,
+    ) -> Self {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT)).unwrap();
         Self {
-            server: TcpListener::bind((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT)).unwrap(),
+            greetings: listen(listener, keypair),
             peer_stream: None,
+            transfers: HashMap::new(),
+            sender: String::new(),
+            download_dir,
+            max_frame_sizes,
+            idle_timeout,
             rx,
             tx,
         }
@@ -37,68 +92,815 @@ impl Server {
 
     pub fn start_server(mut self) -> Result<()> {
         loop {
-            match &mut self.peer_stream {
-                None => {
-                    self.connect_peer()?;
-                }
-                Some(peer) => {
-                    if let Ok(msg) = self.rx.try_recv() {
-                        if let ChannelMessage::Disconnect = msg {
-                            peer.shutdown(Shutdown::Both)?;
-                            self.peer_stream = None;
-                            continue;
-                        }
-                    }
+            match self.peer_stream {
+                None => self.connect_peer()?,
+                // A connection which fails is torn down, the server keeps running
+                Some(_) => match self.receive_frame() {
+                    Ok(()) => (),
+                    Err(err) if is_timeout(&err) => self.check_idle()?,
+                    Err(err) => self.disconnect(Some(format!("{err:#}")))?,
+                },
+            }
+        }
+    }
 
-                    let mut buf = [0u8; 8];
-                    if peer.peek(&mut buf)? != 0 {
-                        let length = u32::from_be_bytes(buf[4..8].try_into().context(
-                            "Malformed Header Recieved: Lenght is not valid 4-byte (32-bit) number",
-                        )?);
+    /// Waits for the next frame of the peer and handles it, tearing the connection down if the
+    /// peer closed it or sent a frame which breaks the protocol
+    fn receive_frame(&mut self) -> Result<()> {
+        let peer = match &mut self.peer_stream {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        let mut buf = [0u8; 8];
+        let peeked = peer.peek(&mut buf)?;
+
+        // Messages from the UI thread are handled before the peeked frame is read,
+        // so a file accepted by the user is registered before its first chunk
+        self.recv_from_channel()?;
+        let peer = match &mut self.peer_stream {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+
+        if peeked != 0 {
+            let length = u32::from_be_bytes(buf[4..8].try_into().context(
+                "Malformed Header Recieved: Lenght is not valid 4-byte (32-bit) number",
+            )?);
+            // The length is checked before anything is allocated for the frame
+            let max_length = self.max_frame_sizes.for_type(&buf[..4]);
+            if 8 + length as u64 > max_length as u64 {
+                let message = format!(
+                    "{} byte {:?} frame is larger than the maximum of {} bytes",
+                    8 + length as u64,
+                    String::from_utf8_lossy(&buf[..4]),
+                    max_length
+                );
+                return self.protocol_error(ProtocolError::new(ErrorCode::TooLarge, message));
+            }
+
+            let mut data = vec![0u8; 8 + length as usize];
+            peer.read_exact(&mut data)?;
+
+            let message = match ProtocolMessage::from_bytes(data) {
+                Ok(message) => message,
+                Err(err) => return self.protocol_error(ProtocolError::decode(&err)),
+            };
+            match message {
+                ProtocolMessage::Message(msg) => self.tx.send(ChannelMessage::Message(msg))?,
+                ProtocolMessage::File(file) => self.tx.send(ChannelMessage::File(file))?,
+                ProtocolMessage::FileOffer(offer) => {
+                    self.tx.send(ChannelMessage::FileOffer(offer))?
+                }
+                ProtocolMessage::FileAccept(accept) => {
+                    self.tx.send(ChannelMessage::FileAccepted(accept))?
+                }
+                ProtocolMessage::FileReject(reject) => {
+                    // The peer can not resume a transfer we asked it to
+                    if let Some(transfer) = self.transfers.remove(&reject.id()) {
+                        transfer.interrupt()?;
+                    }
+                    self.tx.send(ChannelMessage::FileRejected(reject.id()))?
+                }
+                ProtocolMessage::FileResume(resume) => {
+                    self.tx.send(ChannelMessage::ResumeRequested(resume))?
+                }
+                ProtocolMessage::DirOffer(offer) => {
+                    self.tx.send(ChannelMessage::DirOffer(offer))?
+                }
+                ProtocolMessage::FileCancel(cancel) => {
+                    self.cancel_transfer(cancel.id())?;
+                    self.tx.send(ChannelMessage::FileCancelled(cancel.id()))?
+                }
+                ProtocolMessage::Error(error) => {
+                    let reason = format!(
+                        "The peer closed the connection because of a {} we sent: {}",
+                        error.code().description(),
+                        error.message()
+                    );
+                    return self.disconnect(Some(reason));
+                }
+                ProtocolMessage::Ping(ping) => self.tx.send(ChannelMessage::Ping(ping))?,
+                ProtocolMessage::Pong(pong) => {
+                    self.tx.send(ChannelMessage::Latency(pong.round_trip()))?
+                }
+                ProtocolMessage::FileChunk(chunk) => {
+                    let written = match self.transfers.get_mut(&chunk.id()) {
+                        Some(transfer) => transfer.write_chunk(&chunk),
+                        None => return Ok(()),
+                    };
+                    // Only the transfer fails, the UI thread tells the peer to stop sending it
+                    let progress = match written {
+                        Ok(progress) => progress,
+                        Err(err) => return self.fail_transfer(chunk.id(), err),
+                    };
+                    if let Some(progress) = progress {
+                        self.tx.send(ChannelMessage::Progress(progress))?;
+                    }
+                    self.complete_transfer(chunk.id())?
+                }
+            };
+        } else {
+            self.disconnect(None)?;
+        }
+        Ok(())
+    }
 
-                        let mut data = vec![0u8; 8 + length as usize];
-                        peer.read_exact(&mut data)?;
+    /// Runs when a peer which sends pings sent nothing for a while, disconnecting it once it was
+    /// silent for longer than the idle timeout, as it most likely vanished without closing the
+    /// connection. Nothing of a frame which was partly received is lost meanwhile
+    fn check_idle(&mut self) -> Result<()> {
+        self.recv_from_channel()?;
+        let idle_for = match &self.peer_stream {
+            Some(peer) => peer.idle_for(),
+            None => return Ok(()),
+        };
+        if idle_for > self.idle_timeout {
+            let reason = format!("The peer did not answer for {} seconds", idle_for.as_secs());
+            self.disconnect(Some(reason))?;
+        }
+        Ok(())
+    }
 
-                        match ProtocolMessage::from_bytes(data)? {
-                            ProtocolMessage::Message(msg) => {
-                                self.tx.send(ChannelMessage::Message(msg))
+    /// Handles the messages sent by the UI thread while connected to a peer
+    fn recv_from_channel(&mut self) -> Result<()> {
+        while let Ok(message) = self.rx.try_recv() {
+            match message {
+                ChannelMessage::AcceptFile(offer, target) => {
+                    self.start_transfer(&offer, target, true)?;
+                }
+                ChannelMessage::AcceptDir(offer, target) => {
+                    match transfer::create_directory(&offer, &target) {
+                        Ok(files) => {
+                            for (file, target) in files {
+                                self.start_transfer(&file, target, false)?;
                             }
-                            ProtocolMessage::File(file) => self.tx.send(ChannelMessage::File(file)),
-                        }?;
-                    } else {
-                        self.peer_stream = None;
-                        self.tx.send(ChannelMessage::Disconnect)?;
-                        continue;
+                        }
+                        Err(err) => self.tx.send(ChannelMessage::TransferFailed(
+                            offer.id(),
+                            format!("{err:#}"),
+                        ))?,
+                    }
+                }
+                ChannelMessage::ResumeFile(state) => match IncomingTransfer::resume(&state) {
+                    Ok(transfer) => {
+                        self.tx
+                            .send(ChannelMessage::Progress(transfer.progress()))?;
+                        self.transfers.insert(state.id(), transfer);
+                        self.complete_transfer(state.id())?;
                     }
+                    Err(err) => self.tx.send(ChannelMessage::TransferFailed(
+                        state.id(),
+                        format!("{err:#}"),
+                    ))?,
+                },
+                ChannelMessage::CancelFile(id) => self.cancel_transfer(id)?,
+                ChannelMessage::Disconnect(_) => {
+                    if let Some(peer) = self.peer_stream.take() {
+                        peer.shutdown()?;
+                    }
+                    self.interrupt_transfers();
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts receiving an accepted file. Its progress is shown right away if `report` is set,
+    /// the files of a directory are only shown once their chunks arrive
+    fn start_transfer(
+        &mut self,
+        offer: &FileOffer,
+        target: SaveTarget,
+        report: bool,
+    ) -> Result<()> {
+        match IncomingTransfer::new(offer, target, &self.download_dir, &self.sender) {
+            Ok(transfer) => {
+                if report {
+                    self.tx
+                        .send(ChannelMessage::Progress(transfer.progress()))?;
                 }
+                self.transfers.insert(offer.id(), transfer);
+                self.complete_transfer(offer.id())
             }
+            Err(err) => Ok(self.tx.send(ChannelMessage::TransferFailed(
+                offer.id(),
+                format!("{err:#}"),
+            ))?),
+        }
+    }
+
+    /// Sends the file to the UI thread if every chunk of the transfer has been received and its
+    /// content is intact
+    fn complete_transfer(&mut self, id: u32) -> Result<()> {
+        if let Some(transfer) = self.transfers.remove(&id) {
+            if transfer.is_complete() {
+                match transfer.finish() {
+                    Ok(file) => self.tx.send(ChannelMessage::FileReceived(file))?,
+                    Err(err) => self
+                        .tx
+                        .send(ChannelMessage::TransferFailed(id, format!("{err:#}")))?,
+                }
+            } else {
+                self.transfers.insert(id, transfer);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops receiving a cancelled file and removes its partial file
+    fn cancel_transfer(&mut self, id: u32) -> Result<()> {
+        if let Some(transfer) = self.transfers.remove(&id) {
+            if let Err(err) = transfer.cancel() {
+                self.tx
+                    .send(ChannelMessage::TransferFailed(id, format!("{err:#}")))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops receiving a file which could not be written and removes its partial file
+    fn fail_transfer(&mut self, id: u32, err: Error) -> Result<()> {
+        let mut reason = format!("{err:#}");
+        if let Some(Err(err)) = self.transfers.remove(&id).map(IncomingTransfer::cancel) {
+            reason = format!("{reason} ({err:#})");
+        }
+        self.tx.send(ChannelMessage::TransferFailed(id, reason))?;
+        Ok(())
+    }
+
+    /// Tears down the connection of the peer and tells the UI thread, with the reason if it was
+    /// not closed by the peer
+    fn disconnect(&mut self, reason: Option<String>) -> Result<()> {
+        self.close_peer();
+        self.tx.send(ChannelMessage::Disconnect(reason))?;
+        Ok(())
+    }
+
+    /// Tears down the connection of the peer because of a frame it sent, the UI thread tells
+    /// the peer why before closing its own connection
+    fn protocol_error(&mut self, error: ProtocolError) -> Result<()> {
+        self.close_peer();
+        self.tx.send(ChannelMessage::ProtocolError(error))?;
+        Ok(())
+    }
+
+    fn close_peer(&mut self) {
+        if let Some(peer) = self.peer_stream.take() {
+            let _ = peer.shutdown();
+        }
+        self.interrupt_transfers();
+    }
+
+    /// Keeps the partial files of transfers interrupted by a disconnect, so they can be resumed
+    fn interrupt_transfers(&mut self) {
+        for (_, transfer) in self.transfers.drain() {
+            let _ = transfer.interrupt();
         }
     }
 
-    /// Accepts a peer and send message to UI thread for user confirmation on connecting to peer.
-    /// Initiates handshake after confirmation from the user and updates server state.
+    /// Takes the next peer which got through its handshake and send message to UI thread for
+    /// user confirmation on connecting to peer. Papercups clients get the handshake answered and
+    /// the session set up first, so the user can be shown the key of the peer, and are then told
+    /// whether the user accepted the connection. For Tincan clients the answer means the
+    /// connection was accepted, so they only get it once the user accepted, and a handshake with
+    /// the ID 0 otherwise
     fn connect_peer(&mut self) -> Result<()> {
-        let (mut peer, addr) = match self.server.accept() {
-            Ok((peer, addr)) => (peer, addr),
-            Err(_) => return Ok(()),
+        let (addr, greeting) = self.greetings.recv()?;
+        // A peer which fails the handshake was dropped and is reported, the server keeps accepting
+        let (mut peer, handshake, session) = match greeting {
+            Ok(greeting) => greeting,
+            Err(err) => {
+                self.tx
+                    .send(ChannelMessage::ConnectFailed(addr.ip(), format!("{err:#}")))?;
+                return Ok(());
+            }
         };
 
-        peer.set_read_timeout(Some(Duration::from_secs(120)))?;
-        let mut buffer = [0; 9];
-        peer.read(&mut buffer)?;
-        peer.set_read_timeout(None)?;
-
-        let handshake = Handshake::from_bytes(buffer.to_vec())?;
-        self.tx
-            .send(ChannelMessage::ConnectRequest(handshake.id(), addr.ip()))?;
+        self.tx.send(ChannelMessage::ConnectRequest(
+            handshake.id(),
+            addr.ip(),
+            session.as_ref().and_then(|session| session.peer().cloned()),
+        ))?;
+        let sender = transfer::sender_id(addr.ip(), session.as_ref().and_then(Session::peer));
+        let confirms_connection = session.as_ref().is_none_or(Session::confirms_connection);
         if let ChannelMessage::ConnectAccept = self.rx.recv()? {
-            peer.write(&handshake.to_bytes())?;
-            self.peer_stream = Some(peer);
+            // The peer may have given up while it waited for the user
+            if confirms_connection {
+                if let Err(err) = peer.write_all(&handshake.to_bytes()) {
+                    let _ = peer.shutdown(Shutdown::Both);
+                    self.tx
+                        .send(ChannelMessage::ConnectFailed(addr.ip(), err.to_string()))?;
+                    return Ok(());
+                }
+            }
+            let cipher = match session {
+                Some(session) => {
+                    // Reading times out regularly so a peer which stops pinging us is noticed
+                    if session.capabilities().supports(Capabilities::KEEPALIVE) {
+                        peer.set_read_timeout(Some(IDLE_CHECK_INTERVAL))?;
+                    }
+                    session.into_cipher()
+                }
+                None => None,
+            };
+            self.peer_stream = Some(FrameReader::new(peer, cipher));
+            self.sender = sender;
         } else {
-            peer.write(&Handshake::new(0).to_bytes())?;
-            peer.shutdown(Shutdown::Both)?;
+            if confirms_connection {
+                let _ = peer.write_all(&Handshake::new(0).to_bytes());
+            }
+            let _ = peer.shutdown(Shutdown::Both);
         }
 
         Ok(())
     }
 }
+
+/// Accepts the peers connecting to the listener on a thread of its own, and runs the handshake of
+/// every peer on a thread of its own, so a peer which is slow to greet us holds up neither the
+/// server nor the peers connecting after it
+fn listen(listener: TcpListener, keypair: Keypair) -> Receiver<Greeting> {
+    let (tx, rx) = mpsc::sync_channel(MAX_HANDSHAKES);
+    let handshakes = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for peer in listener.incoming() {
+            let peer = match peer {
+                Ok(peer) => peer,
+                Err(_) => continue,
+            };
+            let addr = match peer.peer_addr() {
+                Ok(addr) => addr,
+                Err(_) => continue,
+            };
+            if handshakes.fetch_add(1, Ordering::SeqCst) >= MAX_HANDSHAKES {
+                handshakes.fetch_sub(1, Ordering::SeqCst);
+                let _ = peer.shutdown(Shutdown::Both);
+                let err = anyhow!("Too many peers are connecting at once");
+                let _ = tx.try_send((addr, Err(err)));
+                continue;
+            }
+
+            let (tx, keypair, handshakes) = (tx.clone(), keypair.clone(), handshakes.clone());
+            thread::spawn(move || {
+                greet(peer, addr, &keypair, &tx);
+                handshakes.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    rx
+}
+
+/// Reads the handshake of a peer which connected, and for a Papercups client answers it and
+/// sets up the session, then queues the peer for the server. A peer which fails the handshake
+/// is dropped
+fn greet(mut peer: TcpStream, addr: SocketAddr, keypair: &Keypair, tx: &SyncSender<Greeting>) {
+    let greeting = handshake::read_greeting(&mut peer).and_then(|handshake| {
+        if !handshake.is_papercups() {
+            return Ok((handshake, None));
+        }
+        peer.write_all(&handshake.to_bytes())?;
+        let session = handshake::accept_session(&mut peer, keypair)?;
+        Ok((handshake, Some(session)))
+    });
+    let greeting = match greeting {
+        Ok((handshake, session)) => Ok((peer, handshake, session)),
+        Err(err) => {
+            let _ = peer.shutdown(Shutdown::Both);
+            Err(err)
+        }
+    };
+    let _ = tx.send((addr, greeting));
+}
+
+/// Whether reading from the peer failed because its stream timed out, rather than broke
+fn is_timeout(err: &Error) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|err| matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{ErrorKind, Read, Write},
+        net::{Ipv4Addr, Shutdown, TcpListener, TcpStream},
+        sync::mpsc::{self, Receiver, Sender},
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use super::{
+        cipher::{FrameReader, Keypair},
+        handshake, listen,
+        protocol::{
+            ErrorCode, FileCancel, FileChunk, FileOffer, Handshake, Message, Ping, Pong,
+            ProtocolError, Serializable,
+        },
+        testing::{stream_pair, temp_dir},
+        transfer::{IncomingTransfer, SaveTarget},
+        Server,
+    };
+    use crate::{config::MaxFrameSizes, ChannelMessage};
+
+    /// Server listening on a free port of the loopback interface, with the port, the receiver
+    /// of the messages it sends to the UI thread and the sender of the messages it gets from it
+    fn server() -> (
+        Server,
+        u16,
+        Receiver<ChannelMessage>,
+        Sender<ChannelMessage>,
+    ) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (atx, srx) = mpsc::channel();
+        let (stx, arx) = mpsc::channel();
+        let server = Server {
+            greetings: listen(listener, Keypair::generate().unwrap()),
+            peer_stream: None,
+            transfers: HashMap::new(),
+            sender: String::new(),
+            download_dir: std::env::temp_dir(),
+            max_frame_sizes: MaxFrameSizes::default(),
+            idle_timeout: Duration::from_secs(30),
+            rx: srx,
+            tx: stx,
+        };
+        (server, port, arx, atx)
+    }
+
+    /// Connects to the server and writes the pieces one after another, then stops writing and
+    /// reads until the server closes the connection
+    fn send_pieces(port: u16, pieces: Vec<Vec<u8>>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+            for piece in pieces {
+                stream.write_all(&piece).unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+            let _ = stream.shutdown(Shutdown::Write);
+            let _ = stream.read_to_end(&mut Vec::new());
+        })
+    }
+
+    fn assert_rejected(pieces: Vec<Vec<u8>>) {
+        let (mut server, port, arx, _atx) = server();
+        let peer = send_pieces(port, pieces);
+        server.connect_peer().unwrap();
+        peer.join().unwrap();
+
+        assert!(server.peer_stream.is_none());
+        assert!(matches!(
+            arx.try_recv(),
+            Ok(ChannelMessage::ConnectFailed(_, _))
+        ));
+    }
+
+    /// Server already connected to a peer over the loopback interface, with the stream of the
+    /// peer and the receiver of the messages the server sends to the UI thread
+    fn connected_server(
+        max_frame_sizes: MaxFrameSizes,
+    ) -> (Server, TcpStream, Receiver<ChannelMessage>) {
+        let (mut server, _, arx, _atx) = server();
+        let (stream, peer) = stream_pair();
+        server.peer_stream = Some(FrameReader::new(stream, None));
+        server.max_frame_sizes = max_frame_sizes;
+        (server, peer, arx)
+    }
+
+    #[test]
+    fn receives_frames_within_the_maximum_size() {
+        let max_frame_sizes = MaxFrameSizes {
+            message: 16,
+            ..MaxFrameSizes::default()
+        };
+        let (mut server, mut peer, arx) = connected_server(max_frame_sizes);
+        peer.write_all(&Message::new("12345678".to_string()).to_bytes())
+            .unwrap();
+        server.receive_frame().unwrap();
+
+        assert!(server.peer_stream.is_some());
+        assert!(
+            matches!(arx.try_recv(), Ok(ChannelMessage::Message(msg)) if msg.message() == "12345678")
+        );
+    }
+
+    #[test]
+    fn disconnects_on_oversized_frames() {
+        let max_frame_sizes = MaxFrameSizes {
+            message: 16,
+            ..MaxFrameSizes::default()
+        };
+        let (mut server, mut peer, arx) = connected_server(max_frame_sizes);
+        peer.write_all(&Message::new("123456789".to_string()).to_bytes())
+            .unwrap();
+        server.receive_frame().unwrap();
+
+        assert!(server.peer_stream.is_none());
+        assert!(matches!(
+            arx.try_recv(),
+            Ok(ChannelMessage::ProtocolError(error)) if error.code() == ErrorCode::TooLarge
+        ));
+        // The server closed the connection without waiting for the rest of the frame
+        assert_eq!(peer.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn disconnects_on_hostile_lengths() {
+        let (mut server, mut peer, arx) = connected_server(MaxFrameSizes::default());
+        for msg_type in [b"chat", b"file", b"fchk", b"dofr", b"zzzz"] {
+            let mut header = msg_type.to_vec();
+            header.extend_from_slice(&u32::MAX.to_be_bytes());
+            peer.write_all(&header).unwrap();
+            server.receive_frame().unwrap();
+
+            assert!(server.peer_stream.is_none());
+            assert!(matches!(
+                arx.try_recv(),
+                Ok(ChannelMessage::ProtocolError(error)) if error.code() == ErrorCode::TooLarge
+            ));
+            let (stream, next_peer) = stream_pair();
+            peer = next_peer;
+            server.peer_stream = Some(FrameReader::new(stream, None));
+        }
+    }
+
+    #[test]
+    fn reports_malformed_frames() {
+        let (mut server, mut peer, arx) = connected_server(MaxFrameSizes::default());
+        peer.write_all(b"rjct\0\0\0\x02\0\0").unwrap();
+        server.receive_frame().unwrap();
+
+        assert!(server.peer_stream.is_none());
+        assert!(matches!(
+            arx.try_recv(),
+            Ok(ChannelMessage::ProtocolError(error)) if error.code() == ErrorCode::Malformed
+        ));
+    }
+
+    #[test]
+    fn reports_unsupported_frames() {
+        let (mut server, mut peer, arx) = connected_server(MaxFrameSizes::default());
+        peer.write_all(b"zzzz\0\0\0\x01!").unwrap();
+        server.receive_frame().unwrap();
+
+        assert!(server.peer_stream.is_none());
+        assert!(matches!(
+            arx.try_recv(),
+            Ok(ChannelMessage::ProtocolError(error)) if error.code() == ErrorCode::UnsupportedType
+        ));
+    }
+
+    #[test]
+    fn disconnects_with_the_reason_of_the_peer() {
+        let (mut server, mut peer, arx) = connected_server(MaxFrameSizes::default());
+        let error = ProtocolError::new(ErrorCode::TooLarge, "too much".to_string());
+        peer.write_all(&error.to_bytes()).unwrap();
+        server.receive_frame().unwrap();
+
+        assert!(server.peer_stream.is_none());
+        assert!(matches!(
+            arx.try_recv(),
+            Ok(ChannelMessage::Disconnect(Some(reason))) if reason.ends_with("too much")
+        ));
+    }
+
+    #[test]
+    fn forwards_pings_and_latency() {
+        let (mut server, mut peer, arx) = connected_server(MaxFrameSizes::default());
+        let ping = Ping::now();
+        peer.write_all(&ping.to_bytes()).unwrap();
+        server.receive_frame().unwrap();
+        assert!(matches!(
+            arx.try_recv(),
+            Ok(ChannelMessage::Ping(received)) if received.timestamp() == ping.timestamp()
+        ));
+
+        peer.write_all(&Pong::new(&ping).to_bytes()).unwrap();
+        server.receive_frame().unwrap();
+        assert!(server.peer_stream.is_some());
+        assert!(matches!(arx.try_recv(), Ok(ChannelMessage::Latency(_))));
+    }
+
+    #[test]
+    fn disconnects_silent_peers() {
+        let (mut server, _, arx, _atx) = server();
+        server.idle_timeout = Duration::from_millis(200);
+        let (stream, mut peer) = stream_pair();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        server.peer_stream = Some(FrameReader::new(stream, None));
+
+        // A frame which is cut off by a timeout is still read whole once the rest arrives
+        let frame = Message::new("12345678".to_string()).to_bytes();
+        peer.write_all(&frame[..10]).unwrap();
+        assert!(super::is_timeout(&server.receive_frame().unwrap_err()));
+        server.check_idle().unwrap();
+        peer.write_all(&frame[10..]).unwrap();
+        server.receive_frame().unwrap();
+        assert!(matches!(arx.try_recv(), Ok(ChannelMessage::Message(_))));
+
+        thread::sleep(Duration::from_millis(250));
+        assert!(super::is_timeout(&server.receive_frame().unwrap_err()));
+        server.check_idle().unwrap();
+        assert!(server.peer_stream.is_none());
+        assert!(matches!(
+            arx.try_recv(),
+            Ok(ChannelMessage::Disconnect(Some(reason))) if reason.contains("did not answer")
+        ));
+    }
+
+    #[test]
+    fn accepts_fragmented_handshake() {
+        let (mut server, port, arx, atx) = server();
+        atx.send(ChannelMessage::ConnectAccept).unwrap();
+        let peer = thread::spawn(move || {
+            let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+            let handshake = Handshake::papercups(42).to_bytes();
+            for piece in [&handshake[..3], &handshake[3..7], &handshake[7..]] {
+                stream.write_all(piece).unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+
+            let mut echo = [0u8; Handshake::LENGTH];
+            stream.read_exact(&mut echo).unwrap();
+            assert_eq!(echo.to_vec(), handshake);
+            let keypair = Keypair::generate().unwrap();
+            let session = handshake::connect_session(&mut stream, &keypair).unwrap();
+            assert!(session.confirms_connection());
+            let mut decision = [0u8; Handshake::LENGTH];
+            stream.read_exact(&mut decision).unwrap();
+            assert_eq!(decision.to_vec(), handshake);
+        });
+        server.connect_peer().unwrap();
+        peer.join().unwrap();
+
+        assert!(server.peer_stream.is_some());
+        assert!(matches!(
+            arx.try_recv(),
+            Ok(ChannelMessage::ConnectRequest(id, _, Some(_))) if id == Handshake::papercups(42).id()
+        ));
+    }
+
+    /// Greets the server like a Tincan client and returns what the server answered once the
+    /// user made the `decision`, after checking nothing was answered before
+    fn tincan_answer(decision: ChannelMessage) -> (Server, Vec<u8>) {
+        let (mut server, port, arx, atx) = server();
+        let server = thread::spawn(move || {
+            server.connect_peer().unwrap();
+            server
+        });
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        stream.write_all(&Handshake::new(42).to_bytes()).unwrap();
+        assert!(matches!(
+            arx.recv_timeout(Duration::from_secs(5)),
+            Ok(ChannelMessage::ConnectRequest(42, _, None))
+        ));
+
+        stream.set_nonblocking(true).unwrap();
+        let pending = stream.peek(&mut [0u8; 1]);
+        assert_eq!(pending.unwrap_err().kind(), ErrorKind::WouldBlock);
+        stream.set_nonblocking(false).unwrap();
+
+        atx.send(decision).unwrap();
+        let mut answer = vec![0u8; Handshake::LENGTH];
+        stream.read_exact(&mut answer).unwrap();
+        (server.join().unwrap(), answer)
+    }
+
+    #[test]
+    fn answers_tincan_clients_once_accepted() {
+        let (server, answer) = tincan_answer(ChannelMessage::ConnectAccept);
+        assert_eq!(answer, Handshake::new(42).to_bytes());
+        assert!(server.peer_stream.is_some());
+    }
+
+    #[test]
+    fn rejects_tincan_clients_with_the_id_0() {
+        let (server, answer) = tincan_answer(ChannelMessage::Disconnect(None));
+        assert_eq!(answer, Handshake::new(0).to_bytes());
+        assert!(server.peer_stream.is_none());
+    }
+
+    #[test]
+    fn fails_only_the_transfer_which_can_not_be_written() {
+        let (mut server, mut peer, arx) = connected_server(MaxFrameSizes::default());
+        let id = crate::generate_id();
+        let offer = FileOffer::new(id, 4, [0u8; 32], format!("papercups-{id:08x}"));
+        let target = SaveTarget::new(std::env::temp_dir().join(offer.name()), false);
+        let transfer = IncomingTransfer::new(&offer, target, &server.download_dir, "peer").unwrap();
+        server.transfers.insert(id, transfer);
+
+        peer.write_all(&FileChunk::new(id, vec![1u8; 8]).to_bytes())
+            .unwrap();
+        server.receive_frame().unwrap();
+
+        assert!(server.peer_stream.is_some());
+        assert!(server.transfers.is_empty());
+        assert!(matches!(
+            arx.try_recv(),
+            Ok(ChannelMessage::TransferFailed(failed, _)) if failed == id
+        ));
+    }
+
+    #[test]
+    fn removes_transfers_the_peer_cancelled() {
+        let (mut server, mut peer, arx) = connected_server(MaxFrameSizes::default());
+        let dir = temp_dir();
+        let offer = FileOffer::new(3, 10, [0u8; 32], "notes.txt".to_string());
+        let target = SaveTarget::new(dir.join("notes.txt"), false);
+        let transfer = IncomingTransfer::new(&offer, target, &dir, "peer").unwrap();
+        server.transfers.insert(3, transfer);
+
+        peer.write_all(&FileChunk::new(3, vec![1u8; 4]).to_bytes())
+            .unwrap();
+        server.receive_frame().unwrap();
+        peer.write_all(&FileCancel::new(3).to_bytes()).unwrap();
+        server.receive_frame().unwrap();
+
+        assert!(server.peer_stream.is_some());
+        assert!(server.transfers.is_empty());
+        assert!(matches!(
+            arx.try_recv(),
+            Ok(ChannelMessage::FileCancelled(3))
+        ));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn does_not_wait_for_silent_peers() {
+        let (mut server, port, arx, atx) = server();
+        let _silent = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        stream.write_all(&Handshake::new(42).to_bytes()).unwrap();
+        let server = thread::spawn(move || {
+            let _ = server.connect_peer();
+        });
+
+        // The silent peer is only given up on after the greeting timeout of ten seconds
+        assert!(matches!(
+            arx.recv_timeout(Duration::from_secs(2)),
+            Ok(ChannelMessage::ConnectRequest(42, _, None))
+        ));
+        drop(atx);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rejects_garbage_handshake() {
+        assert_rejected(vec![b"GET / HTTP/1.1\r\n\r\n".to_vec()]);
+        assert_rejected(vec![b"Hel".to_vec(), b"p me\0\0".to_vec()]);
+        assert_rejected(vec![vec![0u8; Handshake::LENGTH]]);
+    }
+
+    #[test]
+    fn rejects_truncated_handshake() {
+        assert_rejected(vec![]);
+        assert_rejected(vec![b"Hell".to_vec()]);
+        assert_rejected(vec![b"Hello".to_vec(), vec![0, 0]]);
+    }
+
+    #[test]
+    fn rejects_oversized_handshake() {
+        let mut greeting = Handshake::new(42).to_bytes();
+        greeting.extend_from_slice(b" and some more");
+        assert_rejected(vec![greeting]);
+    }
+
+    #[test]
+    fn keeps_accepting_after_rejecting_a_peer() {
+        let (mut server, port, arx, atx) = server();
+        let garbage = send_pieces(port, vec![b"garbage!!".to_vec()]);
+        server.connect_peer().unwrap();
+        garbage.join().unwrap();
+        assert!(matches!(
+            arx.try_recv(),
+            Ok(ChannelMessage::ConnectFailed(_, _))
+        ));
+
+        // The user rejects the second peer, which then gets a handshake with the ID 0
+        atx.send(ChannelMessage::Disconnect(None)).unwrap();
+        let peer = thread::spawn(move || {
+            let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+            stream
+                .write_all(&Handshake::papercups(7).to_bytes())
+                .unwrap();
+            let mut echo = [0u8; Handshake::LENGTH];
+            stream.read_exact(&mut echo).unwrap();
+            let keypair = Keypair::generate().unwrap();
+            handshake::connect_session(&mut stream, &keypair).unwrap();
+            let mut decision = [0u8; Handshake::LENGTH];
+            stream.read_exact(&mut decision).unwrap();
+            assert_eq!(decision.to_vec(), Handshake::new(0).to_bytes());
+        });
+        server.connect_peer().unwrap();
+        peer.join().unwrap();
+        assert!(server.peer_stream.is_none());
+        assert!(matches!(
+            arx.try_recv(),
+            Ok(ChannelMessage::ConnectRequest(id, _, Some(_))) if id == Handshake::papercups(7).id()
+        ));
+    }
+}
@@ -0,0 +1,497 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    fs,
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use snow::{
+    params::DHChoice,
+    resolvers::{CryptoResolver, DefaultResolver},
+    Builder, HandshakeState, TransportState,
+};
+
+use crate::{config, network::transfer};
+
+/// Noise protocol the sessions are encrypted with. Both peers prove they own their static key
+/// during the handshake, and every frame is encrypted and authenticated with ChaCha20-Poly1305
+pub const NOISE_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest Noise message, ciphertext and tag included
+const MAX_MESSAGE_LEN: usize = 65535;
+
+/// Length of the authentication tag added to every encrypted record
+const TAG_LEN: usize = 16;
+
+/// Length of the private and the public X25519 key
+const KEY_LEN: usize = 32;
+
+/// Name of the file the key pair is kept in, inside the `papercups` configuration directory
+const IDENTITY_FILE: &str = "identity.key";
+
+/// Static X25519 key pair the peers are identified by
+#[derive(Clone)]
+pub struct Keypair {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl Keypair {
+    pub fn generate() -> Result<Self> {
+        let keypair = Builder::new(NOISE_PATTERN.parse()?).generate_keypair()?;
+        Ok(Self {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+
+    /// Loads the key pair from `identity.key` in the configuration directory, so peers
+    /// recognize us across launches. A key pair is generated and saved on the first launch
+    pub fn load() -> Result<Self> {
+        let path = match config::config_dir() {
+            Some(dir) => dir.join(IDENTITY_FILE),
+            None => return Self::generate(),
+        };
+        if path.exists() {
+            return Self::read(&path);
+        }
+
+        let keypair = Self::generate()?;
+        keypair.save(&path)?;
+        Ok(keypair)
+    }
+
+    pub fn public(&self) -> &[u8] {
+        &self.public
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let malformed = || anyhow!("{} is not a valid key file", path.display());
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read {}", path.display()))?;
+        let mut lines = contents.lines();
+        let mut key = |name: &str| -> Result<Vec<u8>> {
+            let line = lines.next().ok_or_else(malformed)?;
+            match line.split_once('=') {
+                Some((k, value)) if k == name => hex::decode(value).map_err(|_| malformed()),
+                _ => Err(malformed()),
+            }
+        };
+        let private = key("private")?;
+        let public = key("public")?;
+        if private.len() != KEY_LEN || public.len() != KEY_LEN {
+            return Err(malformed());
+        }
+
+        // The public key is derived from the private key, so a file whose keys do not belong
+        // together is refused here instead of failing every handshake
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .context("X25519 is not supported")?;
+        dh.set(&private);
+        if dh.pubkey() != public {
+            return Err(anyhow!(
+                "{}: the public key does not belong to the private key",
+                path.display()
+            ));
+        }
+        Ok(Self {
+            private,
+            public: dh.pubkey().to_vec(),
+        })
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Unable to create {}", dir.display()))?;
+        }
+        let contents = format!(
+            "private={}\npublic={}\n",
+            hex::encode(&self.private),
+            hex::encode(&self.public)
+        );
+        // Only the user may read the private key, so the file is restricted before it is written
+        fs::write(path, "").with_context(|| format!("Unable to write {}", path.display()))?;
+        transfer::set_mode(path, 0o600)?;
+        fs::write(path, contents).with_context(|| format!("Unable to write {}", path.display()))
+    }
+
+    fn builder(&self) -> Result<Builder<'_>> {
+        Ok(Builder::new(NOISE_PATTERN.parse()?).local_private_key(&self.private))
+    }
+
+    /// Starts the handshake of the side which opened the connection. Both sides must pass the
+    /// same `prologue`, the handshake fails otherwise
+    pub fn initiator(&self, prologue: &[u8]) -> Result<HandshakeState> {
+        Ok(self.builder()?.prologue(prologue).build_initiator()?)
+    }
+
+    /// Starts the handshake of the side which accepted the connection
+    pub fn responder(&self, prologue: &[u8]) -> Result<HandshakeState> {
+        Ok(self.builder()?.prologue(prologue).build_responder()?)
+    }
+}
+
+impl Debug for Keypair {
+    // Keeps the private key out of the logs
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public", &hex::encode(&self.public))
+            .finish()
+    }
+}
+
+/// Short form of a public key which users can compare, the first 16 bytes of its SHA-256 hash
+/// in groups of four hex digits
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let hash = Sha256::digest(public_key);
+    hash[..16]
+        .chunks(2)
+        .map(hex::encode)
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Short authentication string of a pair of connections, a six digit number which is the same
+/// for both peers. The peers have a connection to each other's server, and the hashes of both
+/// handshakes are used. They include nonces each side committed to before it learned the nonce
+/// of the other side, so a man in the middle can not search for keys which make the numbers
+/// match on both ends, and only succeeds by chance, once in a million connections
+pub fn short_authentication_string(handshake_hashes: [&[u8]; 2]) -> String {
+    let mut hashes = handshake_hashes;
+    hashes.sort();
+    let mut hasher = Sha256::new();
+    hasher.update(b"papercups-sas");
+    for hash in hashes {
+        hasher.update(hash);
+    }
+    let hash = hasher.finalize();
+    let number = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) % 1_000_000;
+    format!("{:03} {:03}", number / 1000, number % 1000)
+}
+
+/// Writes a handshake message prefixed by its 16-bit length
+pub fn write_handshake(
+    stream: &mut TcpStream,
+    handshake: &mut HandshakeState,
+    payload: &[u8],
+) -> Result<()> {
+    let mut message = vec![0u8; MAX_MESSAGE_LEN];
+    let length = handshake.write_message(payload, &mut message)?;
+    stream.write_all(&(length as u16).to_be_bytes())?;
+    stream.write_all(&message[..length])?;
+    Ok(())
+}
+
+/// Reads a handshake message prefixed by its 16-bit length, returns its payload
+pub fn read_handshake(stream: &mut TcpStream, handshake: &mut HandshakeState) -> Result<Vec<u8>> {
+    let message = read_record(stream)?.context("The peer closed the connection")?;
+    let mut payload = vec![0u8; MAX_MESSAGE_LEN];
+    let length = handshake
+        .read_message(&message, &mut payload)
+        .context("The peer sent an invalid handshake message")?;
+    payload.truncate(length);
+    Ok(payload)
+}
+
+/// Reads a record prefixed by its 16-bit length, `None` if the peer closed the connection
+fn read_record(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 2];
+    match stream.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let mut record = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut record)?;
+    Ok(Some(record))
+}
+
+/// Encrypts frames for the peer once the handshake is done
+pub struct Cipher(TransportState);
+
+impl Cipher {
+    pub fn new(handshake: HandshakeState) -> Result<Self> {
+        Ok(Self(handshake.into_transport_mode()?))
+    }
+
+    /// Encrypts a frame into as many records as it needs, each prefixed by its 16-bit length
+    pub fn encrypt(&mut self, frame: &[u8]) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(frame.len() + frame.len() / 1024 + 32);
+        let mut record = vec![0u8; MAX_MESSAGE_LEN];
+        for plaintext in frame.chunks(MAX_MESSAGE_LEN - TAG_LEN) {
+            let length = self.0.write_message(plaintext, &mut record)?;
+            data.extend_from_slice(&(length as u16).to_be_bytes());
+            data.extend_from_slice(&record[..length]);
+        }
+        Ok(data)
+    }
+
+    fn decrypt(&mut self, record: &[u8]) -> Result<Vec<u8>> {
+        let mut plaintext = vec![0u8; record.len()];
+        let length = self
+            .0
+            .read_message(record, &mut plaintext)
+            .map_err(|_| anyhow!("The peer sent a frame which failed authentication"))?;
+        plaintext.truncate(length);
+        Ok(plaintext)
+    }
+}
+
+impl Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cipher").finish()
+    }
+}
+
+/// Reads the frames sent by the peer, decrypting them if the session is encrypted. If the
+/// stream has a read timeout, reading fails with the timeout error of the stream, and nothing
+/// received so far is lost so reading can be retried
+#[derive(Debug)]
+pub struct FrameReader {
+    stream: TcpStream,
+    cipher: Option<Cipher>,
+    /// Bytes which were received but not read yet
+    buffer: VecDeque<u8>,
+    /// Encrypted bytes which were received but do not make a whole record yet
+    records: Vec<u8>,
+    /// When bytes were last received from the peer
+    last_received: Instant,
+}
+
+impl FrameReader {
+    pub fn new(stream: TcpStream, cipher: Option<Cipher>) -> Self {
+        Self {
+            stream,
+            cipher,
+            buffer: VecDeque::new(),
+            records: Vec::new(),
+            last_received: Instant::now(),
+        }
+    }
+
+    /// How long the peer has not sent anything
+    pub fn idle_for(&self) -> Duration {
+        self.last_received.elapsed()
+    }
+
+    /// Copies the next bytes into `buf` without reading them, waiting until `buf` can be filled
+    /// or the peer closes the connection. Returns the number of bytes copied
+    pub fn peek(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.buffer.len() < buf.len() {
+            if !self.receive()? {
+                break;
+            }
+        }
+        let length = buf.len().min(self.buffer.len());
+        for (byte, peeked) in buf.iter_mut().zip(self.buffer.iter()) {
+            *byte = *peeked;
+        }
+        Ok(length)
+    }
+
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        if self.peek(buf)? < buf.len() {
+            return Err(anyhow!(
+                "The peer closed the connection in the middle of a frame"
+            ));
+        }
+        self.buffer.drain(..buf.len());
+        Ok(())
+    }
+
+    pub fn shutdown(&self) -> Result<()> {
+        Ok(self.stream.shutdown(Shutdown::Both)?)
+    }
+
+    /// Receives more bytes from the peer, returns `false` if the peer closed the connection
+    fn receive(&mut self) -> Result<bool> {
+        let mut data = [0u8; 8192];
+        loop {
+            if let Some(cipher) = &mut self.cipher {
+                if let Some(record) = take_record(&mut self.records) {
+                    self.buffer.extend(cipher.decrypt(&record)?);
+                    return Ok(true);
+                }
+            }
+
+            let length = self.stream.read(&mut data)?;
+            if length == 0 {
+                return Ok(false);
+            }
+            self.last_received = Instant::now();
+            match &self.cipher {
+                Some(_) => self.records.extend_from_slice(&data[..length]),
+                None => {
+                    self.buffer.extend(&data[..length]);
+                    return Ok(true);
+                }
+            }
+        }
+    }
+}
+
+/// Removes the first record from the received bytes, `None` until it was received whole
+fn take_record(received: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let length = u16::from_be_bytes([*received.first()?, *received.get(1)?]) as usize;
+    if received.len() < 2 + length {
+        return None;
+    }
+    let record = received[2..2 + length].to_vec();
+    received.drain(..2 + length);
+    Some(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write, thread, time::Duration};
+
+    use super::{fingerprint, Cipher, FrameReader, Keypair, MAX_MESSAGE_LEN};
+    use crate::network::testing::{stream_pair, temp_dir};
+
+    /// Ciphers of both ends of a session, after running the handshake in memory
+    fn cipher_pair() -> (Cipher, Cipher) {
+        let mut initiator = Keypair::generate().unwrap().initiator(b"test").unwrap();
+        let mut responder = Keypair::generate().unwrap().responder(b"test").unwrap();
+        let (mut message, mut payload) = (vec![0u8; MAX_MESSAGE_LEN], vec![0u8; MAX_MESSAGE_LEN]);
+        for _ in 0..3 {
+            let (writer, reader) = match initiator.is_my_turn() {
+                true => (&mut initiator, &mut responder),
+                false => (&mut responder, &mut initiator),
+            };
+            let length = writer.write_message(&[], &mut message).unwrap();
+            reader
+                .read_message(&message[..length], &mut payload)
+                .unwrap();
+        }
+        (
+            Cipher::new(initiator).unwrap(),
+            Cipher::new(responder).unwrap(),
+        )
+    }
+
+    #[test]
+    fn decrypts_frames_split_across_records_and_reads() {
+        let (mut sender, receiver) = cipher_pair();
+        let (stream, mut peer) = stream_pair();
+        let mut reader = FrameReader::new(stream, Some(receiver));
+
+        // Larger than a record, so it is sent as several
+        let large: Vec<u8> = (0..2 * MAX_MESSAGE_LEN + 100).map(|i| i as u8).collect();
+        let data = [
+            sender.encrypt(b"first").unwrap(),
+            sender.encrypt(&large).unwrap(),
+        ]
+        .concat();
+        let writer = thread::spawn(move || {
+            for piece in data.chunks(1000) {
+                peer.write_all(piece).unwrap();
+            }
+            peer
+        });
+
+        let mut first = [0u8; 5];
+        reader.read_exact(&mut first).unwrap();
+        assert_eq!(&first, b"first");
+        let mut received = vec![0u8; large.len()];
+        reader.read_exact(&mut received).unwrap();
+        assert_eq!(received, large);
+        drop(writer.join().unwrap());
+        assert_eq!(reader.peek(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn keeps_partial_records_when_reading_times_out() {
+        let (mut sender, receiver) = cipher_pair();
+        let (stream, mut peer) = stream_pair();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let mut reader = FrameReader::new(stream, Some(receiver));
+
+        let data = sender.encrypt(b"hello").unwrap();
+        let (head, tail) = data.split_at(3);
+        peer.write_all(head).unwrap();
+        assert!(reader.peek(&mut [0u8; 5]).is_err());
+        peer.write_all(tail).unwrap();
+        let mut frame = [0u8; 5];
+        reader.read_exact(&mut frame).unwrap();
+        assert_eq!(&frame, b"hello");
+    }
+
+    #[test]
+    fn rejects_tampered_records() {
+        let (mut sender, receiver) = cipher_pair();
+        let (stream, mut peer) = stream_pair();
+        let mut reader = FrameReader::new(stream, Some(receiver));
+
+        let mut data = sender.encrypt(b"hello").unwrap();
+        data[4] ^= 1;
+        peer.write_all(&data).unwrap();
+        let err = reader.read_exact(&mut [0u8; 5]).unwrap_err();
+        assert!(err.to_string().contains("failed authentication"));
+    }
+
+    #[test]
+    fn reads_plaintext_frames_as_they_are() {
+        let (stream, mut peer) = stream_pair();
+        let mut reader = FrameReader::new(stream, None);
+        peer.write_all(b"hello").unwrap();
+        drop(peer);
+
+        let mut peeked = [0u8; 8];
+        assert_eq!(reader.peek(&mut peeked).unwrap(), 5);
+        assert_eq!(&peeked[..5], b"hello");
+        assert!(reader.read_exact(&mut [0u8; 8]).is_err());
+    }
+
+    #[test]
+    fn saves_and_reads_key_pairs() {
+        let dir = temp_dir();
+        let path = dir.join("identity.key");
+        let keypair = Keypair::generate().unwrap();
+        keypair.save(&path).unwrap();
+
+        let read = Keypair::read(&path).unwrap();
+        assert_eq!(read.public(), keypair.public());
+        assert_eq!(read.private, keypair.private);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::write(&path, "private=zz\npublic=00\n").unwrap();
+        assert!(Keypair::read(&path).is_err());
+        fs::write(&path, "private=00\npublic=00\n").unwrap();
+        let err = Keypair::read(&path).unwrap_err();
+        assert!(err.to_string().contains(&path.display().to_string()));
+        let other = Keypair::generate().unwrap();
+        let mismatched = format!(
+            "private={}\npublic={}\n",
+            hex::encode(&keypair.private),
+            hex::encode(other.public())
+        );
+        fs::write(&path, mismatched).unwrap();
+        let err = Keypair::read(&path).unwrap_err();
+        assert!(err.to_string().contains(&path.display().to_string()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn fingerprints_keys() {
+        let keypair = Keypair::generate().unwrap();
+        let print = fingerprint(keypair.public());
+        assert_eq!(print.len(), 8 * 4 + 7);
+        assert_eq!(print.split(':').count(), 8);
+        assert_eq!(print, fingerprint(keypair.public()));
+        assert_ne!(print, fingerprint(Keypair::generate().unwrap().public()));
+    }
+}
@@ -0,0 +1,339 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    io::Write,
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::network::{
+    cipher::Cipher,
+    handshake::{PeerIdentity, Session},
+    protocol::{Capabilities, FileChunk, Ping, Serializable},
+};
+
+/// Number of file chunks which can wait to be written before the threads sending files block,
+/// which keeps at most a few hundred KiB of file data in memory
+const MAX_QUEUED_CHUNKS: usize = 4;
+
+/// How often peers which support it are pinged, so they notice if we vanish and we learn the
+/// round trip time
+pub const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Connection to the peer's server, which can be shared between the UI thread and the threads
+/// sending files.
+///
+/// Frames are written by a dedicated thread, so sending never blocks the UI thread, and chat
+/// and control frames are always written before the file chunks waiting in the queue, so
+/// messages get through while files are being transferred
+#[derive(Debug, Clone)]
+pub struct Client {
+    outbox: Arc<OutboxHandle>,
+    peer_addr: SocketAddr,
+    /// Extensions of the Tincan protocol the peer understands
+    capabilities: Capabilities,
+    /// Who the peer proved to be, if the session is encrypted
+    peer: Option<PeerIdentity>,
+}
+
+impl Client {
+    pub fn new(stream: TcpStream, session: Session) -> Result<Self> {
+        let peer_addr = stream.peer_addr()?;
+        let capabilities = session.capabilities();
+        let peer = session.peer().cloned();
+        let cipher = session.into_cipher();
+        let outbox = Arc::new(Outbox::default());
+
+        let writer_outbox = outbox.clone();
+        let keepalive = capabilities.supports(Capabilities::KEEPALIVE);
+        thread::spawn(move || write_frames(stream, cipher, keepalive, &writer_outbox));
+
+        Ok(Self {
+            outbox: Arc::new(OutboxHandle(outbox)),
+            peer_addr,
+            capabilities,
+            peer,
+        })
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    pub fn peer(&self) -> Option<&PeerIdentity> {
+        self.peer.as_ref()
+    }
+
+    /// Closes the connection for every clone of the client, so the threads sending files stop
+    /// streaming. The chat and control frames which were already queued are still written
+    pub fn close(&self) {
+        self.outbox.0.close(None);
+    }
+
+    /// Queues the whole frame ahead of any file chunk, frames sent from different threads
+    /// never interleave
+    pub fn send(&self, msg: &impl Serializable) -> Result<()> {
+        let mut queues = self.outbox.0.lock()?;
+        queues.control.push_back(msg.to_bytes());
+        self.outbox.0.ready.notify_all();
+        Ok(())
+    }
+
+    /// Queues a chunk of a file behind the chat and control frames, blocking while too many
+    /// chunks are already waiting to be written. The chunk is serialized, and maybe
+    /// compressed, before the queues are locked, so other senders are not held up by it
+    pub fn send_chunk(&self, chunk: &FileChunk) -> Result<()> {
+        let frame = chunk.to_bytes();
+        let mut queues = self.outbox.0.lock()?;
+        while queues.data.len() >= MAX_QUEUED_CHUNKS {
+            queues = self
+                .outbox
+                .0
+                .drained
+                .wait(queues)
+                .map_err(|_| anyhow!("Connection to the peer is poisoned"))?;
+            queues.check_open()?;
+        }
+        queues.data.push_back(frame);
+        self.outbox.0.ready.notify_all();
+        Ok(())
+    }
+}
+
+/// Frames waiting to be written to the peer
+#[derive(Debug, Default)]
+struct Outbox {
+    queues: Mutex<Queues>,
+    /// Signalled when a frame is queued or the connection is closed
+    ready: Condvar,
+    /// Signalled when a file chunk has been written or the connection is closed
+    drained: Condvar,
+}
+
+impl Outbox {
+    /// Locks the queues, failing if no more frames can be written
+    fn lock(&self) -> Result<MutexGuard<'_, Queues>> {
+        let queues = self
+            .queues
+            .lock()
+            .map_err(|_| anyhow!("Connection to the peer is poisoned"))?;
+        queues.check_open()?;
+        Ok(queues)
+    }
+
+    fn close(&self, error: Option<String>) {
+        if let Ok(mut queues) = self.queues.lock() {
+            queues.closed = true;
+            if queues.error.is_none() {
+                queues.error = error;
+            }
+        }
+        self.ready.notify_all();
+        self.drained.notify_all();
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    control: VecDeque<Vec<u8>>,
+    data: VecDeque<Vec<u8>>,
+    closed: bool,
+    /// Why writing to the peer failed
+    error: Option<String>,
+}
+
+impl Queues {
+    fn check_open(&self) -> Result<()> {
+        match (&self.error, self.closed) {
+            (Some(error), _) => Err(anyhow!("Connection to the peer failed: {}", error)),
+            (None, true) => Err(anyhow!("Connection to the peer is closed")),
+            (None, false) => Ok(()),
+        }
+    }
+}
+
+/// Closes the connection once every clone of the client has been dropped
+struct OutboxHandle(Arc<Outbox>);
+
+impl Drop for OutboxHandle {
+    fn drop(&mut self) {
+        self.0.close(None);
+    }
+}
+
+impl Debug for OutboxHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutboxHandle").finish()
+    }
+}
+
+/// Writes the queued frames to the peer, chat and control frames first, until the client is
+/// dropped or writing fails. The control frames queued before the client was dropped, like a
+/// last cancellation, are still written. Frames are encrypted if the session is, and the peer
+/// is pinged every `PING_INTERVAL` with `keepalive`
+fn write_frames(
+    mut stream: TcpStream,
+    mut cipher: Option<Cipher>,
+    keepalive: bool,
+    outbox: &Outbox,
+) {
+    let mut next_ping = keepalive.then(|| Instant::now() + PING_INTERVAL);
+    loop {
+        let frame = {
+            let mut queues = match outbox.queues.lock() {
+                Ok(queues) => queues,
+                Err(_) => break,
+            };
+            loop {
+                if let Some(frame) = queues.control.pop_front() {
+                    break Some(frame);
+                }
+                if queues.closed {
+                    break None;
+                }
+                if let Some(at) = next_ping.filter(|at| *at <= Instant::now()) {
+                    next_ping = Some(at + PING_INTERVAL);
+                    break Some(Ping::now().to_bytes());
+                }
+                if let Some(frame) = queues.data.pop_front() {
+                    outbox.drained.notify_all();
+                    break Some(frame);
+                }
+                let waited = match next_ping {
+                    Some(at) => outbox
+                        .ready
+                        .wait_timeout(queues, at.saturating_duration_since(Instant::now()))
+                        .map(|(queues, _)| queues)
+                        .ok(),
+                    None => outbox.ready.wait(queues).ok(),
+                };
+                queues = match waited {
+                    Some(queues) => queues,
+                    None => return,
+                };
+            }
+        };
+
+        match frame {
+            Some(frame) => {
+                let written = match &mut cipher {
+                    Some(cipher) => cipher
+                        .encrypt(&frame)
+                        .and_then(|data| Ok(stream.write_all(&data)?)),
+                    None => stream.write_all(&frame).map_err(Into::into),
+                };
+                if let Err(err) = written {
+                    outbox.close(Some(format!("{err:#}")));
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::Ipv4Addr, sync::Arc, thread};
+
+    use super::{write_frames, Client, Outbox, OutboxHandle, MAX_QUEUED_CHUNKS};
+    use crate::network::{
+        protocol::{Capabilities, FileChunk, Message, Serializable},
+        testing::stream_pair,
+    };
+
+    /// Client whose frames stay in the outbox until a test writes them, with the outbox
+    fn client() -> (Client, Arc<Outbox>) {
+        let outbox = Arc::new(Outbox::default());
+        let client = Client {
+            outbox: Arc::new(OutboxHandle(outbox.clone())),
+            peer_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+            capabilities: Capabilities::legacy(),
+            peer: None,
+        };
+        (client, outbox)
+    }
+
+    #[test]
+    fn writes_messages_before_queued_chunks() {
+        let (client, outbox) = client();
+        let chunks: Vec<_> = (0..3).map(|id| FileChunk::new(id, vec![7u8; 16])).collect();
+        for chunk in &chunks {
+            client.send_chunk(chunk).unwrap();
+        }
+        let message = Message::new("hello".to_string());
+        client.send(&message).unwrap();
+
+        let (stream, mut peer) = stream_pair();
+        let writer = thread::spawn(move || write_frames(stream, None, false, &outbox));
+        let expected: Vec<u8> = [message.to_bytes()]
+            .into_iter()
+            .chain(chunks.iter().map(Serializable::to_bytes))
+            .flatten()
+            .collect();
+        let mut written = vec![0u8; expected.len()];
+        peer.read_exact(&mut written).unwrap();
+        assert_eq!(written, expected);
+
+        drop(client);
+        writer.join().unwrap();
+        assert_eq!(peer.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn blocks_senders_of_too_many_chunks() {
+        let (client, outbox) = client();
+        let sender = {
+            let client = client.clone();
+            thread::spawn(move || -> anyhow::Result<()> {
+                for id in 0..=MAX_QUEUED_CHUNKS as u32 {
+                    client.send_chunk(&FileChunk::new(id, vec![0u8; 16]))?;
+                }
+                Ok(())
+            })
+        };
+        // Once the queue is full the sender can not get any further until a chunk is written
+        let mut queues = outbox.queues.lock().unwrap();
+        while queues.data.len() < MAX_QUEUED_CHUNKS {
+            queues = outbox.ready.wait(queues).unwrap();
+        }
+        drop(queues);
+        assert!(!sender.is_finished());
+
+        // Messages still get through while the chunks wait
+        client.send(&Message::new("hello".to_string())).unwrap();
+        outbox.queues.lock().unwrap().data.pop_front();
+        outbox.drained.notify_all();
+        sender.join().unwrap().unwrap();
+        assert_eq!(outbox.queues.lock().unwrap().data.len(), MAX_QUEUED_CHUNKS);
+    }
+
+    #[test]
+    fn closing_wakes_up_blocked_senders() {
+        let (client, outbox) = client();
+        for id in 0..MAX_QUEUED_CHUNKS as u32 {
+            client
+                .send_chunk(&FileChunk::new(id, vec![0u8; 16]))
+                .unwrap();
+        }
+        let sender = {
+            let client = client.clone();
+            thread::spawn(move || client.send_chunk(&FileChunk::new(9, vec![0u8; 16])))
+        };
+        // The sender fails whether it is already waiting for room or only locks the queues
+        // after they were closed
+        client.close();
+        assert!(sender.join().unwrap().is_err());
+        assert!(client.send(&Message::new("hello".to_string())).is_err());
+        assert!(outbox.queues.lock().unwrap().closed);
+    }
+}
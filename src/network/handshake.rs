@@ -0,0 +1,413 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use snow::HandshakeState;
+
+use crate::network::{
+    cipher::{self, Cipher, Keypair},
+    protocol::{Capabilities, Handshake, Serializable},
+};
+
+/// How long a peer which connected to the server has to send the whole handshake, so a peer
+/// which stalls does not keep other peers from connecting for long
+const GREETING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the capabilities of the peer before falling back to the Tincan protocol
+const CAPABILITIES_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to wait for each message of the Noise handshake
+const NOISE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Length of the nonces the peers commit to during the Noise handshake
+const NONCE_LENGTH: usize = 32;
+
+/// Length of the 'caps' frame sent by this build
+const CAPABILITIES_LENGTH: usize = 14;
+
+/// What was agreed with the peer on a connection, once the handshake is done
+#[derive(Debug)]
+pub struct Session {
+    capabilities: Capabilities,
+    cipher: Option<Cipher>,
+    /// Who the peer proved to be during the Noise handshake
+    peer: Option<PeerIdentity>,
+}
+
+/// Static public key a peer proved to own during the Noise handshake of a connection, and the
+/// hash of that handshake and of the nonces committed to during it, which is the same on both
+/// ends of the connection
+#[derive(Debug, Clone)]
+pub struct PeerIdentity {
+    key: Vec<u8>,
+    handshake_hash: Vec<u8>,
+}
+
+impl PeerIdentity {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn handshake_hash(&self) -> &[u8] {
+        &self.handshake_hash
+    }
+}
+
+impl Session {
+    /// Extensions of the Tincan protocol both peers understand
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    pub fn peer(&self) -> Option<&PeerIdentity> {
+        self.peer.as_ref()
+    }
+
+    /// Whether the server tells the client if the user accepted the connection, by repeating
+    /// the handshake after the session is set up
+    pub fn confirms_connection(&self) -> bool {
+        self.capabilities.version() >= 2
+    }
+
+    /// Cipher of the frames, `None` if the peer can not encrypt them
+    pub fn into_cipher(self) -> Option<Cipher> {
+        self.cipher
+    }
+}
+
+/// Reads the handshake of a peer which connected to the server, which may arrive in several
+/// pieces. Anything else than the magic is rejected as soon as it arrives, and a peer must wait
+/// for the handshake to be answered before sending more, so more data is rejected as well
+pub fn read_greeting(stream: &mut TcpStream) -> Result<Handshake> {
+    let deadline = Instant::now() + GREETING_TIMEOUT;
+    let mut data = [0u8; Handshake::LENGTH];
+    let mut received = 0;
+    while received < data.len() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(anyhow!("The peer did not send the handshake in time"));
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        match stream.read(&mut data[received..]) {
+            Ok(0) => {
+                return Err(anyhow!(
+                    "The peer closed the connection during the handshake"
+                ))
+            }
+            Ok(length) => received += length,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(anyhow!("The peer did not send the handshake in time"))
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let magic = received.min(Handshake::MAGIC.len());
+        if data[..magic] != Handshake::MAGIC[..magic] {
+            return Err(anyhow!(
+                "The peer sent {:?} instead of a handshake",
+                String::from_utf8_lossy(&data[..received])
+            ));
+        }
+    }
+    stream.set_read_timeout(None)?;
+
+    stream.set_nonblocking(true)?;
+    let pending = stream.peek(&mut [0u8; 1]);
+    stream.set_nonblocking(false)?;
+    if let Ok(length) = pending {
+        if length > 0 {
+            return Err(anyhow!("The peer sent more than a handshake"));
+        }
+    }
+    Handshake::from_bytes(data.to_vec()).context("Malformed Handshake message")
+}
+
+/// Sets up a session on a connection to the server of the peer, after the handshake. The
+/// capabilities are exchanged, then the Noise handshake is run as the initiator if both peers
+/// can encrypt the session. The exchanged capabilities are the prologue of the Noise handshake,
+/// so it fails if a man in the middle tampered with them
+pub fn connect_session(stream: &mut TcpStream, keypair: &Keypair) -> Result<Session> {
+    let (capabilities, exchange) = request_capabilities(stream)?;
+    let (peer, cipher) = if capabilities.supports(Capabilities::ENCRYPTION) {
+        let mut handshake = keypair.initiator(&exchange)?;
+        let nonce: [u8; NONCE_LENGTH] = rand::random();
+        stream.set_read_timeout(Some(NOISE_TIMEOUT))?;
+        cipher::write_handshake(stream, &mut handshake, &commitment(&nonce))?;
+        let peer_nonce = cipher::read_handshake(stream, &mut handshake)?;
+        cipher::write_handshake(stream, &mut handshake, &nonce)?;
+        stream.set_read_timeout(None)?;
+        let peer = identity(&handshake, &nonce, &peer_nonce)?;
+        (peer, Some(Cipher::new(handshake)?))
+    } else {
+        (None, None)
+    };
+    Ok(Session {
+        capabilities,
+        cipher,
+        peer,
+    })
+}
+
+/// Sets up a session on a connection accepted by the server, after the handshake, running the
+/// Noise handshake as the responder if both peers can encrypt the session
+pub fn accept_session(stream: &mut TcpStream, keypair: &Keypair) -> Result<Session> {
+    let (capabilities, exchange) = offer_capabilities(stream)?;
+    let (peer, cipher) = if capabilities.supports(Capabilities::ENCRYPTION) {
+        let mut handshake = keypair.responder(&exchange)?;
+        let nonce: [u8; NONCE_LENGTH] = rand::random();
+        stream.set_read_timeout(Some(NOISE_TIMEOUT))?;
+        let peer_commitment = cipher::read_handshake(stream, &mut handshake)?;
+        cipher::write_handshake(stream, &mut handshake, &nonce)?;
+        let peer_nonce = cipher::read_handshake(stream, &mut handshake)?;
+        stream.set_read_timeout(None)?;
+        if commitment(&peer_nonce) != peer_commitment {
+            return Err(anyhow!(
+                "The peer revealed another nonce than it committed to"
+            ));
+        }
+        let peer = identity(&handshake, &peer_nonce, &nonce)?;
+        (peer, Some(Cipher::new(handshake)?))
+    } else {
+        (None, None)
+    };
+    Ok(Session {
+        capabilities,
+        cipher,
+        peer,
+    })
+}
+
+/// Commitment to the nonce of the initiator, which it sends in the first Noise message and opens
+/// in the last one, after the responder sent its own nonce
+fn commitment(nonce: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"papercups-commitment");
+    hasher.update(nonce);
+    hasher.finalize().to_vec()
+}
+
+/// Who the peer proved to be once the Noise handshake is done. The hash of the handshake covers
+/// the nonces of both sides, the initiator's first
+fn identity(
+    handshake: &HandshakeState,
+    initiator_nonce: &[u8],
+    responder_nonce: &[u8],
+) -> Result<Option<PeerIdentity>> {
+    if initiator_nonce.len() != NONCE_LENGTH || responder_nonce.len() != NONCE_LENGTH {
+        return Err(anyhow!("The peer sent a nonce of an invalid length"));
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(handshake.get_handshake_hash());
+    hasher.update(initiator_nonce);
+    hasher.update(responder_nonce);
+    Ok(handshake.get_remote_static().map(|key| PeerIdentity {
+        key: key.to_vec(),
+        handshake_hash: hasher.finalize().to_vec(),
+    }))
+}
+
+/// Exchanges capabilities on a connection to the server of the peer, after the handshake.
+/// A papercups server sends its capabilities first and gets ours in reply, while a Tincan server
+/// sends nothing, in which case only the Tincan protocol is used. The exchanged 'caps' frames
+/// are returned as well, the server's first
+fn request_capabilities(stream: &mut TcpStream) -> Result<(Capabilities, Vec<u8>)> {
+    let (peer, mut exchange) = match read_capabilities(stream)? {
+        Some(peer) => peer,
+        None => return Ok((Capabilities::legacy(), Vec::new())),
+    };
+    let capabilities = Capabilities::current();
+    let frame = capabilities.to_bytes();
+    stream.write_all(&frame)?;
+    exchange.extend_from_slice(&frame);
+    Ok((capabilities.negotiate(&peer), exchange))
+}
+
+/// Exchanges capabilities on a connection accepted by the server, after the handshake. A Tincan
+/// client ignores the capabilities sent to it and never replies, in which case only the Tincan
+/// protocol is used. The exchanged 'caps' frames are returned as well, ours first
+fn offer_capabilities(stream: &mut TcpStream) -> Result<(Capabilities, Vec<u8>)> {
+    let capabilities = Capabilities::current();
+    let mut exchange = capabilities.to_bytes();
+    stream.write_all(&exchange)?;
+    Ok(match read_capabilities(stream)? {
+        Some((peer, frame)) => {
+            exchange.extend_from_slice(&frame);
+            (capabilities.negotiate(&peer), exchange)
+        }
+        None => (Capabilities::legacy(), Vec::new()),
+    })
+}
+
+/// Reads the 'caps' frame of the peer with its bytes, `None` if the peer does not send one in
+/// time. Any other frame is left unread for the protocol
+fn read_capabilities(stream: &mut TcpStream) -> Result<Option<(Capabilities, Vec<u8>)>> {
+    stream.set_read_timeout(Some(CAPABILITIES_TIMEOUT))?;
+    let capabilities = peek_capabilities(stream);
+    stream.set_read_timeout(None)?;
+    capabilities
+}
+
+fn peek_capabilities(stream: &mut TcpStream) -> Result<Option<(Capabilities, Vec<u8>)>> {
+    let deadline = Instant::now() + CAPABILITIES_TIMEOUT;
+    let mut header = [0u8; 8];
+    loop {
+        match stream.peek(&mut header) {
+            Ok(8) => break,
+            Ok(0) => return Ok(None),
+            // The rest of the header is still on its way
+            Ok(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            Ok(_) => return Ok(None),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    if &header[..4] != b"caps" {
+        return Ok(None);
+    }
+
+    let length = u32::from_be_bytes(header[4..].try_into()?) as usize;
+    if length < CAPABILITIES_LENGTH - 8 || length > u16::MAX as usize {
+        return Err(anyhow!(
+            "Malformed Capabilities message: invalid length {}",
+            length
+        ));
+    }
+    let mut data = vec![0u8; 8 + length];
+    stream.read_exact(&mut data)?;
+    let capabilities =
+        Capabilities::from_bytes(data.clone()).context("Malformed Capabilities message")?;
+    Ok(Some((capabilities, data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Read, Write},
+        net::{Shutdown, TcpStream},
+        thread::{self, JoinHandle},
+    };
+
+    use anyhow::Result;
+
+    use super::{accept_session, connect_session, Session};
+    use crate::network::{
+        cipher::{self, Keypair},
+        protocol::{Capabilities, Serializable},
+        testing::stream_pair,
+    };
+
+    fn accept_in_background(mut stream: TcpStream) -> JoinHandle<Result<Session>> {
+        thread::spawn(move || accept_session(&mut stream, &Keypair::generate()?))
+    }
+
+    #[test]
+    fn sets_up_encrypted_sessions() {
+        let (mut client, server) = stream_pair();
+        let server = accept_in_background(server);
+        let client = connect_session(&mut client, &Keypair::generate().unwrap()).unwrap();
+        let server = server.join().unwrap().unwrap();
+
+        assert!(client.capabilities().supports(Capabilities::ENCRYPTION));
+        assert_eq!(
+            client.peer().unwrap().handshake_hash(),
+            server.peer().unwrap().handshake_hash()
+        );
+    }
+
+    /// Session of a connection from the client to the server, as seen by both
+    fn session(client: &Keypair, server: &Keypair) -> (Session, Session) {
+        let (mut client_stream, mut server_stream) = stream_pair();
+        let server = server.clone();
+        let accepted = thread::spawn(move || accept_session(&mut server_stream, &server));
+        let connected = connect_session(&mut client_stream, client).unwrap();
+        (connected, accepted.join().unwrap().unwrap())
+    }
+
+    fn sas(client: &Session, server: &Session) -> String {
+        let hashes = [client, server].map(|session| session.peer().unwrap().handshake_hash());
+        cipher::short_authentication_string(hashes)
+    }
+
+    #[test]
+    fn both_peers_compute_the_same_short_authentication_string() {
+        let (alice, bob) = (Keypair::generate().unwrap(), Keypair::generate().unwrap());
+        let (alice_client, bob_server) = session(&alice, &bob);
+        let (bob_client, alice_server) = session(&bob, &alice);
+
+        let sas_of_alice = sas(&alice_client, &alice_server);
+        assert_eq!(sas_of_alice, sas(&bob_client, &bob_server));
+        assert_eq!(sas_of_alice.len(), 7);
+        assert!(sas_of_alice
+            .split(' ')
+            .all(|digits| digits.len() == 3 && digits.chars().all(|c| c.is_ascii_digit())));
+
+        // Every connection gets a handshake hash of its own, as the nonces differ, and the
+        // order the hashes are passed in does not matter
+        let (next_client, next_server) = session(&alice, &bob);
+        assert_ne!(
+            next_client.peer().unwrap().handshake_hash(),
+            alice_client.peer().unwrap().handshake_hash()
+        );
+        assert_eq!(
+            sas(&next_client, &next_server),
+            sas(&next_server, &next_client)
+        );
+    }
+
+    #[test]
+    fn rejects_broken_commitments() {
+        let (mut client, server) = stream_pair();
+        let server = accept_in_background(server);
+
+        let mut caps = [0u8; super::CAPABILITIES_LENGTH];
+        client.read_exact(&mut caps).unwrap();
+        let reply = Capabilities::current().to_bytes();
+        client.write_all(&reply).unwrap();
+        let keypair = Keypair::generate().unwrap();
+        let mut handshake = keypair.initiator(&[&caps[..], &reply].concat()).unwrap();
+
+        // The nonce revealed in the last message is not the one committed to in the first
+        let nonce = [1u8; super::NONCE_LENGTH];
+        let committed = super::commitment(&nonce);
+        cipher::write_handshake(&mut client, &mut handshake, &committed).unwrap();
+        cipher::read_handshake(&mut client, &mut handshake).unwrap();
+        cipher::write_handshake(&mut client, &mut handshake, &[2u8; super::NONCE_LENGTH]).unwrap();
+
+        let err = server.join().unwrap().unwrap_err();
+        assert!(err.to_string().contains("committed"));
+    }
+
+    #[test]
+    fn fails_when_the_capabilities_were_tampered_with() {
+        let (mut client, client_side) = stream_pair();
+        let (proxy, server) = stream_pair();
+        let server = accept_in_background(server);
+
+        // A man in the middle clears a feature in the capabilities of the server
+        let mut downstream = (proxy.try_clone().unwrap(), client_side.try_clone().unwrap());
+        thread::spawn(move || {
+            let mut caps = [0u8; super::CAPABILITIES_LENGTH];
+            downstream.0.read_exact(&mut caps)?;
+            caps[super::CAPABILITIES_LENGTH - 1] &= !(Capabilities::COMPRESSION as u8);
+            downstream.1.write_all(&caps)?;
+            io::copy(&mut downstream.0, &mut downstream.1)
+        });
+        let mut upstream = (client_side, proxy);
+        thread::spawn(move || {
+            io::copy(&mut upstream.0, &mut upstream.1)?;
+            upstream.1.shutdown(Shutdown::Both)
+        });
+
+        assert!(connect_session(&mut client, &Keypair::generate().unwrap()).is_err());
+        drop(client);
+        assert!(server.join().unwrap().is_err());
+    }
+}
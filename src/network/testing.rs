@@ -0,0 +1,20 @@
+use std::{
+    env, fs,
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    path::PathBuf,
+};
+
+/// Both ends of a connection over the loopback interface
+pub fn stream_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    (stream, peer)
+}
+
+/// Empty directory of its own in the temporary directory, for a single test
+pub fn temp_dir() -> PathBuf {
+    let dir = env::temp_dir().join(format!("papercups-test-{:08x}", crate::generate_id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,1305 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{mpsc::Sender, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Error, Result};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{expand_home, CollisionPolicy},
+    network::{
+        cipher,
+        client::Client,
+        handshake::PeerIdentity,
+        protocol::{FileChunk, FileOffer},
+    },
+    ChannelMessage,
+};
+
+mod directory;
+mod limit;
+mod names;
+
+pub use directory::{create_directory, send_directory, OutgoingDirectory};
+pub use limit::{parse_rate, parse_size, RateLimits};
+pub use names::{sanitize_file_name, sanitize_relative_path};
+
+/// Maximum number of file bytes carried by a single 'fchk' frame
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Minimum time between two progress reports of the same transfer
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Identifies the peer which sends a file in the state file of its transfer, so it is only
+/// resumed with that peer. Peers are identified by the fingerprint of their key if the session
+/// is encrypted, and by their address otherwise
+pub fn sender_id(ip: IpAddr, peer: Option<&PeerIdentity>) -> String {
+    match peer {
+        Some(peer) => cipher::fingerprint(peer.key()),
+        None => ip.to_string(),
+    }
+}
+
+/// Creates the directory received files are saved into if needed
+pub fn create_download_dir(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Unable to create directory {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Returns the path a file sent by the peer under the given name is saved to, which is always
+/// directly inside the download directory
+pub fn download_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    create_download_dir(dir)?;
+    let path = dir.join(sanitize_file_name(name)?);
+    if path.parent() != Some(dir) {
+        return Err(anyhow!(
+            "{} is outside of the download directory",
+            path.display()
+        ));
+    }
+    ensure_not_symlink(&path)?;
+    Ok(path)
+}
+
+/// Resolves a path entered by the user to save a file sent by the peer under the given name.
+/// Relative paths are inside the download directory, and the file keeps its name if the path is
+/// a directory
+pub fn save_as_path(dir: &Path, input: &str, name: &str) -> Result<PathBuf> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(anyhow!("No path was entered"));
+    }
+
+    let mut path = dir.join(expand_home(Path::new(input)));
+    if path.is_dir() || input.ends_with(['/', '\\']) {
+        path.push(sanitize_file_name(name)?);
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Unable to create directory {}", parent.display()))?;
+    }
+    ensure_not_symlink(&path)?;
+    Ok(path)
+}
+
+/// Refuses to write through a symbolic link, which could point outside of the download directory
+fn ensure_not_symlink(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => Err(anyhow!(
+            "{} is a symbolic link, refusing to write through it",
+            path.display()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Path of the partial file in the download directory a file is written to until it is
+/// completely received
+fn part_path(dir: &Path, name: &str, id: u32) -> Result<PathBuf> {
+    create_download_dir(dir)?;
+    let path = dir.join(format!("{name}.{id:08x}.part"));
+    ensure_not_symlink(&path)?;
+    Ok(path)
+}
+
+/// Path of a temporary file next to `path`, used to write its content before moving it there
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".part");
+    PathBuf::from(temp_path)
+}
+
+/// Returns `path` if nothing exists there, otherwise the first of `name (1).ext`,
+/// `name (2).ext`, ... which does not exist yet
+pub fn unique_path(path: &Path) -> PathBuf {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return path.to_path_buf(),
+    };
+    // The extension starts at the first dot which does not start the name, so
+    // `archive.tar.gz` becomes `archive (1).tar.gz` and `.bashrc` becomes `.bashrc (1)`
+    let (stem, extension) = match name.char_indices().skip(1).find(|(_, c)| *c == '.') {
+        Some((index, _)) => name.split_at(index),
+        None => (name, ""),
+    };
+
+    let mut unique_path = path.to_path_buf();
+    let mut n = 1;
+    while unique_path.exists() {
+        unique_path.set_file_name(format!("{stem} ({n}){extension}"));
+        n += 1;
+    }
+    unique_path
+}
+
+/// Where a received file is saved, whether a file which already exists there may be
+/// overwritten and the permissions it gets
+#[derive(Debug, Clone)]
+pub struct SaveTarget {
+    path: PathBuf,
+    overwrite: bool,
+    mode: u32,
+}
+
+impl SaveTarget {
+    pub fn new(path: PathBuf, overwrite: bool) -> Self {
+        Self {
+            path,
+            overwrite,
+            mode: 0,
+        }
+    }
+
+    /// Gives the saved file the Unix permission bits of the file sent by the peer, a `mode` of
+    /// 0 keeps the default permissions
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Target of a received file whose name may already be taken by another file, following
+    /// the collision policy. `None` if the file is skipped
+    pub fn for_collision(path: PathBuf, policy: CollisionPolicy) -> Option<Self> {
+        match policy {
+            _ if !path.exists() => Some(Self::new(path, false)),
+            CollisionPolicy::Rename => Some(Self::new(path, false)),
+            CollisionPolicy::Prompt => Some(Self::new(path, true)),
+            CollisionPolicy::Skip => None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the file replaces a file which exists at the target
+    pub fn overwrites(&self) -> bool {
+        self.overwrite && self.path.exists()
+    }
+
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Moves the completely written temporary file to the target in a single rename, so a crash
+    /// never leaves a half-written file under the name of the target. An existing file is only
+    /// replaced if overwriting was allowed, otherwise the file is saved under a unique name
+    pub fn persist(&self, temp: &Path) -> Result<PathBuf> {
+        let path = if self.overwrite {
+            self.path.clone()
+        } else {
+            unique_path(&self.path)
+        };
+        ensure_not_symlink(&path)?;
+
+        match fs::rename(temp, &path) {
+            Ok(()) => (),
+            // The target is on another file system, so the file is copied next to it first
+            Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+                let staged = temp_path(&path);
+                fs::copy(temp, &staged)
+                    .with_context(|| format!("Unable to write {}", staged.display()))?;
+                fs::rename(&staged, &path)
+                    .with_context(|| format!("Unable to save {}", path.display()))?;
+                fs::remove_file(temp)
+                    .with_context(|| format!("Unable to remove {}", temp.display()))?;
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Unable to save {}", path.display()))
+            }
+        }
+        set_mode(&path, self.mode)?;
+        Ok(path)
+    }
+
+    /// Writes the whole content of a file to a temporary file and moves it to the target
+    pub fn write(&self, data: &[u8]) -> Result<PathBuf> {
+        let temp = temp_path(&self.path);
+        ensure_not_symlink(&temp)?;
+        fs::write(&temp, data).with_context(|| format!("Unable to write {}", temp.display()))?;
+        self.persist(&temp)
+    }
+}
+
+/// Permission bits a received file may get from the peer. The setuid, setgid and sticky bits are
+/// never taken over, so a peer can not plant a setuid program in the download directory
+pub const FILE_MODE_MASK: u32 = 0o777;
+
+/// Permission bits a received directory may get from the peer, which can not make it writable
+/// by anyone else than the owner
+pub const DIR_MODE_MASK: u32 = 0o755;
+
+/// Sets the Unix permission bits of a received file or directory, a `mode` of 0 or a platform
+/// without them keeps the default permissions. Only the bits of `FILE_MODE_MASK` are applied
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if mode != 0 {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & FILE_MODE_MASK))
+            .with_context(|| format!("Unable to set the permissions of {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Returns the Unix permission bits of a file or directory which is sent to the peer, 0 on
+/// platforms without them
+#[cfg(unix)]
+pub fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & FILE_MODE_MASK
+}
+
+#[cfg(not(unix))]
+pub fn mode(_metadata: &fs::Metadata) -> u32 {
+    0
+}
+
+/// A file which is being streamed to the peer, read from the disk one chunk at a time
+#[derive(Debug)]
+pub struct OutgoingTransfer {
+    id: u32,
+    name: String,
+    path: PathBuf,
+    size: u64,
+    hash: [u8; 32],
+    offset: u64,
+    file: fs::File,
+    /// Whether the chunks are compressed on the wire
+    compress: bool,
+}
+
+impl OutgoingTransfer {
+    /// Opens a file and hashes it, which reads all of it. It is called on its own thread, so
+    /// a large file does not block the UI
+    pub fn new(path: &Path) -> Result<Self> {
+        let mut transfer = Self::open_file(path, crate::generate_id(), [0; 32])?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut transfer.file, &mut hasher)
+            .with_context(|| format!("Unable to read {}", path.display()))?;
+        transfer.file.seek(SeekFrom::Start(0))?;
+        transfer.hash = hasher.finalize().into();
+        Ok(transfer)
+    }
+
+    /// Opens a file which was hashed when it was offered to the peer, like a file of a
+    /// directory, without reading it again
+    pub fn open(path: &Path, offer: &FileOffer) -> Result<Self> {
+        let transfer = Self::open_file(path, offer.id(), offer.hash())?;
+        if transfer.size != offer.size() {
+            return Err(anyhow!(
+                "{} has changed since it was offered to the peer",
+                path.display()
+            ));
+        }
+        Ok(transfer)
+    }
+
+    fn open_file(path: &Path, id: u32, hash: [u8; 32]) -> Result<Self> {
+        if !path.is_file() {
+            return Err(anyhow!("{} is not a file", path.display()));
+        }
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} does not have a valid UTF-8 name", path.display()))?
+            .to_string();
+        let file =
+            fs::File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            id,
+            name,
+            path: path.to_path_buf(),
+            size,
+            hash,
+            offset: 0,
+            file,
+            compress: false,
+        })
+    }
+
+    /// Reopens a file which was offered to the peer, like the file of an interrupted transfer,
+    /// to stream it from `offset`
+    pub fn resume(path: &Path, id: u32, hash: [u8; 32], offset: u64) -> Result<Self> {
+        let mut transfer = Self::new(path)?;
+        if transfer.hash != hash {
+            return Err(anyhow!(
+                "{} has changed since it was offered to the peer",
+                path.display()
+            ));
+        }
+        if offset > transfer.size {
+            return Err(anyhow!(
+                "The peer asked to resume {} past its end",
+                path.display()
+            ));
+        }
+        transfer.file.seek(SeekFrom::Start(offset))?;
+        transfer.id = id;
+        transfer.offset = offset;
+        Ok(transfer)
+    }
+
+    /// Compresses the chunks if the peer can receive compressed chunks, unless the file is of
+    /// a type which is already compressed
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compress = enabled && !is_compressed_file(&self.name);
+        self
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        self.hash
+    }
+
+    pub fn offer(&self) -> FileOffer {
+        FileOffer::new(self.id, self.size, self.hash, self.name.clone())
+    }
+
+    /// Progress of the transfer before any chunk has been sent
+    pub fn progress(&self) -> TransferProgress {
+        ProgressTracker::new(
+            self.id,
+            self.name.clone(),
+            TransferDirection::Send,
+            self.offset,
+            self.size,
+        )
+        .progress()
+    }
+
+    /// Reads the next chunk of the file, returns `None` once as many bytes as were offered have
+    /// been read. Fails if the file shrank since it was offered, anything it grew by is not sent
+    pub fn next_chunk(&mut self) -> Result<Option<FileChunk>> {
+        let remaining = self.size - self.offset;
+        if remaining == 0 {
+            return Ok(None);
+        }
+        let mut data = vec![0u8; CHUNK_SIZE];
+        let length = (&mut self.file)
+            .take(remaining)
+            .read(&mut data)
+            .with_context(|| format!("Unable to read {}", self.name))?;
+        if length == 0 {
+            return Err(anyhow!(
+                "{} has shrunk since it was offered to the peer",
+                self.name
+            ));
+        }
+        self.offset += length as u64;
+        data.truncate(length);
+        let chunk = FileChunk::new(self.id, data);
+        Ok(Some(if self.compress {
+            chunk.compressed()
+        } else {
+            chunk
+        }))
+    }
+}
+
+/// Extensions of file types whose data is already compressed, which do not get any smaller
+/// when they are compressed again
+const COMPRESSED_EXTENSIONS: [&str; 29] = [
+    "7z", "aac", "apk", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "jar", "jpeg", "jpg",
+    "lz", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "png", "rar", "tgz", "webm", "webp",
+    "xz", "zip", "zst",
+];
+
+fn is_compressed_file(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+/// IDs of the transfers cancelled by either side, shared with the threads sending files so they
+/// stop streaming them
+pub type CancelledTransfers = Arc<Mutex<HashSet<u32>>>;
+
+pub fn is_cancelled(cancelled: &CancelledTransfers, id: u32) -> bool {
+    cancelled
+        .lock()
+        .is_ok_and(|cancelled| cancelled.contains(&id))
+}
+
+/// Streams an accepted file to the peer in chunks, reporting its progress to the UI thread
+pub fn send_file(
+    client: &Client,
+    mut transfer: OutgoingTransfer,
+    tx: &Sender<ChannelMessage>,
+    cancelled: &CancelledTransfers,
+    limits: &RateLimits,
+) -> Result<()> {
+    let mut tracker = ProgressTracker::new(
+        transfer.id,
+        transfer.name.clone(),
+        TransferDirection::Send,
+        transfer.offset,
+        transfer.size,
+    );
+    tracker.limit = limits.limit(transfer.id);
+    tx.send(ChannelMessage::Progress(tracker.progress()))?;
+
+    while let Some(chunk) = transfer.next_chunk()? {
+        if is_cancelled(cancelled, transfer.id) {
+            return Ok(());
+        }
+        limits.throttle(transfer.id, chunk.data().len() as u64);
+        tracker.limit = limits.limit(transfer.id);
+        client
+            .send_chunk(&chunk)
+            .with_context(|| format!("Unable to send {}", transfer.name))?;
+        if let Some(progress) = tracker.advance(chunk.data().len() as u64) {
+            tx.send(ChannelMessage::Progress(progress))?;
+        }
+    }
+    tx.send(ChannelMessage::FileSent(transfer.id))?;
+    Ok(())
+}
+
+/// A file which is being received from the peer, written to a partial file in the download
+/// directory as the chunks arrive
+#[derive(Debug)]
+pub struct IncomingTransfer {
+    id: u32,
+    name: String,
+    size: u64,
+    hash: [u8; 32],
+    hasher: Sha256,
+    received: u64,
+    part_path: PathBuf,
+    target: SaveTarget,
+    /// Who sends the file, see `sender_id`
+    sender: String,
+    file: fs::File,
+    tracker: ProgressTracker,
+}
+
+impl IncomingTransfer {
+    /// Starts receiving an accepted file from the sender into a partial file in the download
+    /// directory
+    pub fn new(
+        offer: &FileOffer,
+        target: SaveTarget,
+        download_dir: &Path,
+        sender: &str,
+    ) -> Result<Self> {
+        let name = target.name();
+        let part_path = part_path(download_dir, &name, offer.id())?;
+        let file = fs::File::create(&part_path)
+            .with_context(|| format!("Unable to create {}", part_path.display()))?;
+
+        let transfer = Self {
+            id: offer.id(),
+            name: name.clone(),
+            size: offer.size(),
+            hash: offer.hash(),
+            hasher: Sha256::new(),
+            received: 0,
+            part_path,
+            target,
+            sender: sender.to_string(),
+            file,
+            tracker: ProgressTracker::new(
+                offer.id(),
+                name,
+                TransferDirection::Receive,
+                0,
+                offer.size(),
+            ),
+        };
+        transfer.save_state()?;
+        Ok(transfer)
+    }
+
+    /// Reopens the partial file of an interrupted transfer, keeping only the data up to the
+    /// offset recorded in its state file
+    pub fn resume(state: &ResumeState) -> Result<Self> {
+        let part_path = state.part_path.clone();
+        ensure_not_symlink(&part_path)?;
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&part_path)
+            .with_context(|| format!("Unable to open {}", part_path.display()))?;
+
+        let offset = state.offset.min(file.metadata()?.len());
+        file.set_len(offset)?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut (&mut file).take(offset), &mut hasher)
+            .with_context(|| format!("Unable to read {}", part_path.display()))?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            id: state.id,
+            name: state.target.name(),
+            size: state.size,
+            hash: state.hash,
+            hasher,
+            received: offset,
+            part_path,
+            target: state.target.clone(),
+            sender: state.sender.clone().unwrap_or_default(),
+            file,
+            tracker: ProgressTracker::new(
+                state.id,
+                state.target.name(),
+                TransferDirection::Receive,
+                offset,
+                state.size,
+            ),
+        })
+    }
+
+    pub fn progress(&self) -> TransferProgress {
+        self.tracker.progress()
+    }
+
+    /// Writes the chunk to the partial file, returns the progress when it is due to be reported
+    pub fn write_chunk(&mut self, chunk: &FileChunk) -> Result<Option<TransferProgress>> {
+        let data = chunk.data();
+        if self.received + data.len() as u64 > self.size {
+            return Err(anyhow!(
+                "Received more data than announced for {}",
+                self.name
+            ));
+        }
+        self.file
+            .write_all(data)
+            .with_context(|| format!("Unable to write {}", self.part_path.display()))?;
+        self.hasher.update(data);
+        self.received += data.len() as u64;
+
+        let progress = self.tracker.advance(data.len() as u64);
+        if progress.is_some() {
+            self.save_state()?;
+        }
+        Ok(progress)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.size
+    }
+
+    /// Stops receiving the file and removes its partial file, so nothing is left of it
+    pub fn cancel(self) -> Result<()> {
+        let state_path = state_path(&self.part_path);
+        drop(self.file);
+        if state_path.exists() {
+            fs::remove_file(&state_path)
+                .with_context(|| format!("Unable to remove {}", state_path.display()))?;
+        }
+        fs::remove_file(&self.part_path)
+            .with_context(|| format!("Unable to remove {}", self.part_path.display()))
+    }
+
+    /// Verifies the hash of the completely received file and moves it from its partial file to
+    /// its target, the partial file is removed if the content does not match the offer
+    pub fn finish(mut self) -> Result<ReceivedFile> {
+        self.file.flush()?;
+        drop(self.file);
+        fs::remove_file(state_path(&self.part_path))?;
+        let hash: [u8; 32] = self.hasher.finalize().into();
+        if hash != self.hash {
+            fs::remove_file(&self.part_path)
+                .with_context(|| format!("Unable to remove {}", self.part_path.display()))?;
+            return Err(anyhow!(
+                "{} is corrupted, its SHA-256 hash does not match the one offered by the peer",
+                self.name
+            ));
+        }
+
+        let path = self.target.persist(&self.part_path)?;
+        Ok(ReceivedFile {
+            id: self.id,
+            name: self.name,
+            path,
+        })
+    }
+
+    /// Keeps the partial file and its state file, so the transfer can be resumed later
+    pub fn interrupt(self) -> Result<()> {
+        self.save_state()
+    }
+
+    /// Records the number of bytes safely written to the partial file in its state file
+    fn save_state(&self) -> Result<()> {
+        self.file
+            .sync_data()
+            .with_context(|| format!("Unable to write {}", self.part_path.display()))?;
+        let state = ResumeState {
+            id: self.id,
+            size: self.size,
+            hash: self.hash,
+            offset: self.received,
+            part_path: self.part_path.clone(),
+            target: self.target.clone(),
+            sender: Some(self.sender.clone()),
+        };
+        state.save(&state_path(&self.part_path))
+    }
+}
+
+/// Returns the state of every interrupted transfer in the download directory, along with the
+/// errors of the state files which could not be loaded and were skipped
+pub fn interrupted_transfers(download_dir: &Path) -> Result<(Vec<ResumeState>, Vec<Error>)> {
+    let mut states = Vec::new();
+    let mut errors = Vec::new();
+    if !download_dir.is_dir() {
+        return Ok((states, errors));
+    }
+    for entry in fs::read_dir(download_dir)? {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(".part.state") {
+            match ResumeState::load(&path) {
+                Ok(state) => states.push(state),
+                Err(err) => errors.push(err),
+            }
+        }
+    }
+    Ok((states, errors))
+}
+
+/// Path of the state file kept next to a partial file
+fn state_path(part_path: &Path) -> PathBuf {
+    let mut path = part_path.as_os_str().to_owned();
+    path.push(".state");
+    PathBuf::from(path)
+}
+
+/// Contents of the state file of a partial file, which has everything needed to ask the peer
+/// to resume its transfer
+#[derive(Debug, Clone)]
+pub struct ResumeState {
+    id: u32,
+    size: u64,
+    hash: [u8; 32],
+    offset: u64,
+    part_path: PathBuf,
+    target: SaveTarget,
+    /// Who sends the file, see `sender_id`. Not recorded by older versions
+    sender: Option<String>,
+}
+
+impl ResumeState {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> String {
+        self.target.name()
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        self.hash
+    }
+
+    /// Number of bytes of the partial file which were written to the disk
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Whether the file was being received from the sender, see `sender_id`
+    pub fn is_from(&self, sender: &str) -> bool {
+        self.sender.as_deref() == Some(sender)
+    }
+
+    /// Removes the partial file of the interrupted transfer and its state, after which it can
+    /// no longer be resumed
+    pub fn discard(&self) -> Result<()> {
+        for path in [state_path(&self.part_path), self.part_path.clone()] {
+            if path.exists() {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Unable to remove {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let malformed = || anyhow!("{} is not a valid state file", path.display());
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read {}", path.display()))?;
+        let mut lines = contents.lines();
+        let mut field = |key: &str| -> Result<String> {
+            let line = lines.next().ok_or_else(malformed)?;
+            match line.split_once('=') {
+                Some((k, value)) if k == key => Ok(value.to_string()),
+                _ => Err(malformed()),
+            }
+        };
+
+        let part_path = path.with_extension("");
+        Ok(Self {
+            id: field("id")?.parse().map_err(|_| malformed())?,
+            size: field("size")?.parse().map_err(|_| malformed())?,
+            hash: hex::decode(field("hash")?)
+                .ok()
+                .and_then(|hash| hash.try_into().ok())
+                .ok_or_else(malformed)?,
+            offset: field("offset")?.parse().map_err(|_| malformed())?,
+            target: SaveTarget::new(
+                PathBuf::from(field("path")?),
+                field("overwrite")?.parse().map_err(|_| malformed())?,
+            )
+            .with_mode(field("mode")?.parse().map_err(|_| malformed())?),
+            part_path,
+            sender: field("sender").ok(),
+        })
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut contents = format!(
+            "id={}\nsize={}\nhash={}\noffset={}\npath={}\noverwrite={}\nmode={}\n",
+            self.id,
+            self.size,
+            hex::encode(self.hash),
+            self.offset,
+            self.target.path.display(),
+            self.target.overwrite,
+            self.target.mode
+        );
+        if let Some(sender) = &self.sender {
+            contents.push_str(&format!("sender={sender}\n"));
+        }
+        fs::write(path, contents).with_context(|| format!("Unable to write {}", path.display()))
+    }
+}
+
+/// A file which has been completely received and saved into the download directory
+#[derive(Debug)]
+pub struct ReceivedFile {
+    id: u32,
+    name: String,
+    path: PathBuf,
+}
+
+impl ReceivedFile {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Whether a transfer sends a file to the peer or receives a file from the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    Send,
+    Receive,
+}
+
+/// Snapshot of the progress of a transfer, reported to the UI thread
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+    id: u32,
+    name: String,
+    direction: TransferDirection,
+    done: u64,
+    total: u64,
+    rate: f64,
+    limit: Option<u64>,
+    eta: Option<Duration>,
+}
+
+impl TransferProgress {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn direction(&self) -> TransferDirection {
+        self.direction
+    }
+
+    /// Number of bytes transferred so far
+    pub fn done(&self) -> u64 {
+        self.done
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Average transfer rate in bytes per second
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Rate in bytes per second the upload is limited to
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    pub fn set_limit(&mut self, limit: Option<u64>) {
+        self.limit = limit;
+    }
+
+    /// Estimated time until the transfer completes, `None` while the rate is unknown
+    pub fn eta(&self) -> Option<Duration> {
+        self.eta
+    }
+
+    pub fn ratio(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.done as f64 / self.total as f64
+        }
+    }
+}
+
+/// Keeps track of the transferred bytes of a transfer and when its progress was last reported
+#[derive(Debug)]
+struct ProgressTracker {
+    id: u32,
+    name: String,
+    direction: TransferDirection,
+    /// Bytes which were already transferred before this tracker started, when resuming
+    start: u64,
+    done: u64,
+    total: u64,
+    /// Rate the upload is limited to, when it was last sent a chunk
+    limit: Option<u64>,
+    started: Instant,
+    last_report: Instant,
+}
+
+impl ProgressTracker {
+    fn new(id: u32, name: String, direction: TransferDirection, done: u64, total: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            id,
+            name,
+            direction,
+            start: done,
+            done,
+            total,
+            limit: None,
+            started: now,
+            last_report: now,
+        }
+    }
+
+    /// Records the transferred bytes, returns the progress if it is due to be reported
+    fn advance(&mut self, bytes: u64) -> Option<TransferProgress> {
+        // Transfers queued behind others, like the files of a directory, measure their rate from
+        // their first chunk
+        if self.done == self.start {
+            self.started = Instant::now();
+        }
+        self.done += bytes;
+        if self.done >= self.total || self.last_report.elapsed() >= PROGRESS_INTERVAL {
+            self.last_report = Instant::now();
+            Some(self.progress())
+        } else {
+            None
+        }
+    }
+
+    fn remaining(&self) -> u64 {
+        self.total.saturating_sub(self.done)
+    }
+
+    fn progress(&self) -> TransferProgress {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            (self.done - self.start) as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = (rate > 0.0).then(|| Duration::from_secs_f64(self.remaining() as f64 / rate));
+
+        TransferProgress {
+            id: self.id,
+            name: self.name.clone(),
+            direction: self.direction,
+            done: self.done,
+            total: self.total,
+            rate,
+            limit: self.limit,
+            eta,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use sha2::{Digest, Sha256};
+
+    use super::{
+        create_download_dir, download_path, interrupted_transfers, save_as_path, unique_path,
+        IncomingTransfer, OutgoingTransfer, SaveTarget, CHUNK_SIZE,
+    };
+    use crate::{
+        config::CollisionPolicy,
+        network::{
+            protocol::{FileChunk, FileOffer, Serializable},
+            testing::temp_dir,
+        },
+    };
+
+    #[test]
+    fn saves_into_the_download_directory() {
+        let dir = temp_dir();
+        let downloads = dir.join("downloads");
+        let path = download_path(&downloads, "/tmp/notes.txt").unwrap();
+        assert_eq!(path, downloads.join("notes.txt"));
+        assert!(downloads.is_dir());
+        assert!(download_path(&downloads, "../notes.txt").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_to_save_through_symbolic_links() {
+        let dir = temp_dir();
+        std::os::unix::fs::symlink("/etc/passwd", dir.join("notes.txt")).unwrap();
+        assert!(download_path(&dir, "notes.txt").is_err());
+        assert!(save_as_path(&dir, "notes.txt", "notes.txt").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolves_save_as_paths() {
+        let dir = temp_dir();
+        assert_eq!(
+            save_as_path(&dir, " report.pdf ", "notes.txt").unwrap(),
+            dir.join("report.pdf")
+        );
+        // A directory keeps the name of the file, and is created if it ends with a separator
+        assert_eq!(
+            save_as_path(&dir, "papers/", "notes.txt").unwrap(),
+            dir.join("papers").join("notes.txt")
+        );
+        assert!(dir.join("papers").is_dir());
+        assert_eq!(
+            save_as_path(&dir, "papers", "notes.txt").unwrap(),
+            dir.join("papers").join("notes.txt")
+        );
+        let absolute = dir.join("elsewhere.txt");
+        assert_eq!(
+            save_as_path(
+                Path::new("/nonexistent"),
+                absolute.to_str().unwrap(),
+                "notes.txt"
+            )
+            .unwrap(),
+            absolute
+        );
+        assert!(save_as_path(&dir, "  ", "notes.txt").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn numbers_names_which_are_taken() {
+        let dir = temp_dir();
+        let path = dir.join("archive.tar.gz");
+        assert_eq!(unique_path(&path), path);
+        fs::write(&path, b"").unwrap();
+        fs::write(dir.join("archive (1).tar.gz"), b"").unwrap();
+        assert_eq!(unique_path(&path), dir.join("archive (2).tar.gz"));
+
+        let dotfile = dir.join(".bashrc");
+        fs::write(&dotfile, b"").unwrap();
+        assert_eq!(unique_path(&dotfile), dir.join(".bashrc (1)"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reports_files_which_can_not_be_read_or_written() {
+        let dir = temp_dir();
+        let missing = dir.join("missing.txt");
+        assert!(OutgoingTransfer::new(&missing).is_err());
+        assert!(OutgoingTransfer::new(&dir).is_err());
+
+        // A file where the download directory should be
+        let blocked = dir.join("blocked");
+        fs::write(&blocked, b"").unwrap();
+        assert!(create_download_dir(&blocked).is_err());
+        assert!(download_path(&blocked, "notes.txt").is_err());
+        let offer = FileOffer::new(1, 1, [0u8; 32], "notes.txt".to_string());
+        let target = SaveTarget::new(blocked.join("notes.txt"), false);
+        assert!(IncomingTransfer::new(&offer, target.clone(), &blocked, "peer").is_err());
+        assert!(target.write(b"notes").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Receives the content in two chunks into `dir`, for a file offered with the hash
+    fn receive(dir: &Path, content: &[u8], hash: [u8; 32]) -> IncomingTransfer {
+        let offer = FileOffer::new(7, content.len() as u64, hash, "notes.txt".to_string());
+        let target = SaveTarget::new(dir.join("notes.txt"), false);
+        let mut transfer = IncomingTransfer::new(&offer, target, dir, "192.0.2.1").unwrap();
+        let (first, second) = content.split_at(content.len() / 2);
+        for data in [first, second] {
+            transfer
+                .write_chunk(&FileChunk::new(7, data.to_vec()))
+                .unwrap();
+        }
+        assert!(transfer.is_complete());
+        transfer
+    }
+
+    #[test]
+    fn saves_files_whose_hash_matches() {
+        let dir = temp_dir();
+        let content = b"The quick brown fox";
+        let transfer = receive(&dir, content, Sha256::digest(content).into());
+
+        let file = transfer.finish().unwrap();
+        assert_eq!(file.path(), dir.join("notes.txt"));
+        assert_eq!(fs::read(file.path()).unwrap(), content);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn discards_files_whose_hash_does_not_match() {
+        let dir = temp_dir();
+        let content = b"The quick brown fox";
+        let transfer = receive(&dir, content, Sha256::digest(b"The quick brown cat").into());
+
+        let err = transfer.finish().unwrap_err();
+        assert!(err.to_string().contains("is corrupted"));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn removes_the_partial_files_of_cancelled_transfers() {
+        let dir = temp_dir();
+        let transfer = receive(&dir, b"The quick brown fox", [0u8; 32]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        transfer.cancel().unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn discards_interrupted_transfers() {
+        let dir = temp_dir();
+        receive(&dir, b"The quick brown fox", [0u8; 32])
+            .interrupt()
+            .unwrap();
+        let (states, errors) = interrupted_transfers(&dir).unwrap();
+        assert_eq!(states.len(), 1);
+        assert!(errors.is_empty());
+        assert_eq!(states[0].offset(), 19);
+
+        states[0].discard().unwrap();
+        assert!(interrupted_transfers(&dir).unwrap().0.is_empty());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_more_data_than_offered() {
+        let dir = temp_dir();
+        let offer = FileOffer::new(7, 4, [0u8; 32], "notes.txt".to_string());
+        let target = SaveTarget::new(dir.join("notes.txt"), false);
+        let mut transfer = IncomingTransfer::new(&offer, target, &dir, "192.0.2.1").unwrap();
+        assert!(transfer
+            .write_chunk(&FileChunk::new(7, vec![1; 5]))
+            .is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn drops_special_mode_bits_of_received_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir();
+        let target = SaveTarget::new(dir.join("tool"), false).with_mode(0o4755);
+        let path = target.write(b"#!/bin/sh\n").unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o755);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn records_who_sends_interrupted_files() {
+        let dir = temp_dir();
+        let offer = FileOffer::new(7, 10, [0u8; 32], "notes.txt".to_string());
+        let target = SaveTarget::new(dir.join("notes.txt"), false);
+        let transfer = IncomingTransfer::new(&offer, target, &dir, "192.0.2.1").unwrap();
+        drop(transfer);
+
+        let (states, _) = interrupted_transfers(&dir).unwrap();
+        assert_eq!(states.len(), 1);
+        assert!(states[0].is_from("192.0.2.1"));
+        assert!(!states[0].is_from("192.0.2.2"));
+
+        // State files of older versions do not name the sender, and are never resumed
+        let state_path = dir.join("notes.txt.00000007.part.state");
+        let contents = fs::read_to_string(&state_path).unwrap();
+        let (older, _) = contents.split_once("sender=").unwrap();
+        fs::write(&state_path, older).unwrap();
+        let (states, _) = interrupted_transfers(&dir).unwrap();
+        assert!(!states[0].is_from("192.0.2.1"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_state_files_which_can_not_be_loaded() {
+        let dir = temp_dir();
+        receive(&dir, b"The quick brown fox", [0u8; 32])
+            .interrupt()
+            .unwrap();
+        let bad_path = dir.join("broken.part.state");
+        fs::write(&bad_path, "id=zz\n").unwrap();
+
+        let (states, errors) = interrupted_transfers(&dir).unwrap();
+        assert_eq!(states.len(), 1);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0]
+            .to_string()
+            .contains(&bad_path.display().to_string()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sends_only_the_offered_bytes() {
+        let dir = temp_dir();
+        let path = dir.join("growing");
+        fs::write(&path, vec![1u8; CHUNK_SIZE + 10]).unwrap();
+        let mut transfer = OutgoingTransfer::new(&path).unwrap();
+        fs::write(&path, vec![1u8; 2 * CHUNK_SIZE]).unwrap();
+
+        let first = transfer.next_chunk().unwrap().unwrap();
+        assert_eq!(first.data().len(), CHUNK_SIZE);
+        let second = transfer.next_chunk().unwrap().unwrap();
+        assert_eq!(second.data().len(), 10);
+        assert!(transfer.next_chunk().unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reopens_offered_files_unless_they_changed() {
+        let dir = temp_dir();
+        let path = dir.join("notes.txt");
+        fs::write(&path, b"notes").unwrap();
+        let offer = FileOffer::new(7, 5, [1u8; 32], "notes.txt".to_string());
+
+        let transfer = OutgoingTransfer::open(&path, &offer).unwrap();
+        assert_eq!((transfer.id(), transfer.hash()), (7, [1u8; 32]));
+        fs::write(&path, b"longer notes").unwrap();
+        assert!(OutgoingTransfer::open(&path, &offer).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn fails_when_the_file_shrank() {
+        let dir = temp_dir();
+        let path = dir.join("shrinking");
+        fs::write(&path, vec![1u8; CHUNK_SIZE + 10]).unwrap();
+        let mut transfer = OutgoingTransfer::new(&path).unwrap();
+        fs::write(&path, vec![1u8; 10]).unwrap();
+
+        assert_eq!(transfer.next_chunk().unwrap().unwrap().data().len(), 10);
+        assert!(transfer.next_chunk().is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn follows_the_collision_policy() {
+        let dir = temp_dir();
+        let free = dir.join("free.txt");
+        for policy in [
+            CollisionPolicy::Rename,
+            CollisionPolicy::Prompt,
+            CollisionPolicy::Skip,
+        ] {
+            let target = SaveTarget::for_collision(free.clone(), policy).unwrap();
+            assert!(!target.overwrites());
+        }
+
+        let taken = dir.join("taken.txt");
+        fs::write(&taken, b"old").unwrap();
+        assert!(SaveTarget::for_collision(taken.clone(), CollisionPolicy::Skip).is_none());
+
+        let renamed = SaveTarget::for_collision(taken.clone(), CollisionPolicy::Rename).unwrap();
+        assert!(!renamed.overwrites());
+        assert_eq!(renamed.write(b"new").unwrap(), dir.join("taken (1).txt"));
+        assert_eq!(fs::read(&taken).unwrap(), b"old");
+
+        let replaced = SaveTarget::for_collision(taken.clone(), CollisionPolicy::Prompt).unwrap();
+        assert!(replaced.overwrites());
+        assert_eq!(replaced.write(b"new").unwrap(), taken);
+        assert_eq!(fs::read(&taken).unwrap(), b"new");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn persists_by_renaming_the_temporary_file() {
+        let dir = temp_dir();
+        let temp = dir.join("notes.txt.tmp");
+        fs::write(&temp, b"notes").unwrap();
+        let target = SaveTarget::new(dir.join("notes.txt"), false);
+
+        assert_eq!(target.persist(&temp).unwrap(), dir.join("notes.txt"));
+        assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), b"notes");
+        assert!(!temp.exists());
+
+        // Only a target on another file system is copied, other failures are reported
+        assert!(target.persist(&temp).is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn does_not_compress_compressed_files() {
+        let dir = temp_dir();
+        for (name, frame_type) in [("notes.txt", b"fchz"), ("photo.JPG", b"fchk")] {
+            let path = dir.join(name);
+            fs::write(&path, b"papercups ".repeat(1000)).unwrap();
+            let mut transfer = OutgoingTransfer::new(&path).unwrap().with_compression(true);
+            let frame = transfer.next_chunk().unwrap().unwrap().to_bytes();
+            assert_eq!(&frame[..4], frame_type);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+};
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    network::{
+        client::Client,
+        protocol::{DirEntry, DirOffer, FileOffer, FileReject},
+        transfer::{self, CancelledTransfers, OutgoingTransfer, RateLimits, SaveTarget},
+    },
+    ChannelMessage,
+};
+
+use super::sanitize_relative_path;
+
+/// A directory tree which is offered to the peer. Its files are streamed one after another
+/// once the peer accepts it
+#[derive(Debug)]
+pub struct OutgoingDirectory {
+    offer: DirOffer,
+    /// Path and offer of every file in the tree
+    files: Vec<(PathBuf, FileOffer)>,
+    /// Whether the peer can receive compressed chunks
+    compress: bool,
+}
+
+impl OutgoingDirectory {
+    /// Walks the tree and hashes every file in it, which reads all of them. It is called on
+    /// its own thread, so a large tree does not block the UI
+    pub fn new(path: &Path) -> Result<Self> {
+        if !path.is_dir() {
+            return Err(anyhow!("{} is not a directory", path.display()));
+        }
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} does not have a valid UTF-8 name", path.display()))?
+            .to_string();
+
+        let mut entries = Vec::new();
+        let mut files = Vec::new();
+        walk(path, "", &mut entries, &mut files)?;
+        Ok(Self {
+            offer: DirOffer::new(crate::generate_id(), name, entries),
+            files,
+            compress: false,
+        })
+    }
+
+    /// Compresses the files which are not already compressed, once the peer accepted
+    /// compressed chunks
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compress = enabled;
+        self
+    }
+
+    pub fn id(&self) -> u32 {
+        self.offer.id()
+    }
+
+    pub fn name(&self) -> &str {
+        self.offer.name()
+    }
+
+    pub fn offer(&self) -> &DirOffer {
+        &self.offer
+    }
+
+    /// Path and offer of every file in the tree
+    pub fn files(&self) -> &[(PathBuf, FileOffer)] {
+        &self.files
+    }
+}
+
+/// Adds the entries inside `dir` to the offer, in a stable order and with every directory
+/// before its content. Symbolic links are skipped, as they could lead out of the tree
+fn walk(
+    dir: &Path,
+    prefix: &str,
+    entries: &mut Vec<DirEntry>,
+    files: &mut Vec<(PathBuf, FileOffer)>,
+) -> Result<()> {
+    let mut children = fs::read_dir(dir)
+        .with_context(|| format!("Unable to read {}", dir.display()))?
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("Unable to read {}", dir.display()))?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let path = child.path();
+        let name = child
+            .file_name()
+            .into_string()
+            .map_err(|_| anyhow!("{} does not have a valid UTF-8 name", path.display()))?;
+        let relative_path = format!("{prefix}{name}");
+        let metadata = fs::symlink_metadata(&path)
+            .with_context(|| format!("Unable to read {}", path.display()))?;
+
+        if metadata.is_dir() {
+            entries.push(DirEntry::directory(
+                relative_path.clone(),
+                transfer::mode(&metadata),
+            ));
+            walk(&path, &format!("{relative_path}/"), entries, files)?;
+        } else if metadata.is_file() {
+            let mut file = fs::File::open(&path)
+                .with_context(|| format!("Unable to open {}", path.display()))?;
+            let mut hasher = Sha256::new();
+            io::copy(&mut file, &mut hasher)
+                .with_context(|| format!("Unable to read {}", path.display()))?;
+
+            let offer = FileOffer::new(
+                crate::generate_id(),
+                metadata.len(),
+                hasher.finalize().into(),
+                relative_path,
+            );
+            entries.push(DirEntry::file(transfer::mode(&metadata), offer.clone()));
+            files.push((path, offer));
+        }
+    }
+    Ok(())
+}
+
+/// Streams the files of an accepted directory to the peer one after another. A file which can
+/// not be read is rejected, so the peer stops waiting for it, and the next file is sent.
+/// Cancelled files are skipped. The files are not hashed again, a file which changed since it
+/// was offered fails on the peer's side when its hash does not match
+pub fn send_directory(
+    client: &Client,
+    directory: OutgoingDirectory,
+    tx: &Sender<ChannelMessage>,
+    cancelled: &CancelledTransfers,
+    limits: &RateLimits,
+) -> Result<()> {
+    for (path, offer) in directory.files {
+        let id = offer.id();
+        if transfer::is_cancelled(cancelled, id) {
+            continue;
+        }
+        let transfer = match OutgoingTransfer::open(&path, &offer) {
+            Ok(transfer) => transfer.with_compression(directory.compress),
+            Err(err) => {
+                client.send(&FileReject::new(id))?;
+                tx.send(ChannelMessage::TransferFailed(id, format!("{err:#}")))?;
+                continue;
+            }
+        };
+        if let Err(err) = transfer::send_file(client, transfer, tx, cancelled, limits) {
+            tx.send(ChannelMessage::TransferFailed(id, format!("{err:#}")))?;
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Creates the directory a tree sent by the peer is saved to, with every directory inside it,
+/// and returns the targets its files are saved to. Unless overwriting was allowed, the tree is
+/// saved under a unique name if the target already exists
+pub fn create_directory(
+    offer: &DirOffer,
+    target: &SaveTarget,
+) -> Result<Vec<(FileOffer, SaveTarget)>> {
+    let (root, overwrite) = if target.overwrite {
+        (target.path.clone(), true)
+    } else {
+        (transfer::unique_path(&target.path), false)
+    };
+    if let Some(parent) = root.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Unable to create directory {}", parent.display()))?;
+    }
+    create_dir(&root)?;
+
+    let mut files = Vec::new();
+    for entry in offer.entries() {
+        let relative_path = sanitize_relative_path(entry.path())?;
+        // Every directory on the way is checked, so a symbolic link left in a tree which is
+        // overwritten can not redirect the files out of it
+        let mut path = root.clone();
+        let mut components = relative_path.iter().peekable();
+        while let Some(component) = components.next() {
+            path.push(component);
+            if components.peek().is_some() || entry.offer().is_none() {
+                create_dir(&path)?;
+            }
+        }
+        match entry.offer() {
+            Some(file) => files.push((
+                file.clone(),
+                SaveTarget::new(path, overwrite).with_mode(entry.mode()),
+            )),
+            None => {
+                // The owner keeps the permission to write into the directory, as its files
+                // are received after it is created
+                if entry.mode() != 0 {
+                    let mode = (entry.mode() & transfer::DIR_MODE_MASK) | 0o700;
+                    transfer::set_mode(&path, mode)?;
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Creates a directory of a received tree, or reuses it if it already exists as a directory
+fn create_dir(path: &Path) -> Result<()> {
+    transfer::ensure_not_symlink(path)?;
+    match fs::create_dir(path) {
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => Ok(()),
+        result => result.with_context(|| format!("Unable to create directory {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{create_directory, OutgoingDirectory};
+    use crate::network::{
+        protocol::{DirEntry, DirOffer, FileOffer},
+        testing::temp_dir,
+        transfer::SaveTarget,
+    };
+
+    #[test]
+    fn lists_the_offered_files_with_their_hashes() {
+        let dir = temp_dir();
+        let tree = dir.join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join("sub").join("notes.txt"), b"notes").unwrap();
+        fs::write(tree.join("a.txt"), b"a").unwrap();
+
+        let directory = OutgoingDirectory::new(&tree).unwrap();
+        let names: Vec<_> = directory
+            .files()
+            .iter()
+            .map(|(_, offer)| offer.name())
+            .collect();
+        assert_eq!(names, ["a.txt", "sub/notes.txt"]);
+        let offered: Vec<_> = directory.offer().files().collect();
+        for ((_, offer), offered) in directory.files().iter().zip(offered) {
+            assert_eq!(offer.id(), offered.id());
+            assert_eq!(offer.hash(), offered.hash());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symbolic_links_in_overwritten_trees() {
+        let dir = temp_dir();
+        let outside = dir.join("outside");
+        let tree = dir.join("tree");
+        fs::create_dir_all(&outside).unwrap();
+        fs::create_dir_all(&tree).unwrap();
+        std::os::unix::fs::symlink(&outside, tree.join("sub")).unwrap();
+
+        let file = FileOffer::new(1, 5, [0u8; 32], "sub/notes.txt".to_string());
+        let target = SaveTarget::new(tree.clone(), true);
+        let nested = DirOffer::new(2, "tree".to_string(), vec![DirEntry::file(0, file)]);
+        assert!(create_directory(&nested, &target).is_err());
+        let entries = vec![DirEntry::directory("sub".to_string(), 0o755)];
+        let direct = DirOffer::new(3, "tree".to_string(), entries);
+        assert!(create_directory(&direct, &target).is_err());
+
+        fs::remove_file(tree.join("sub")).unwrap();
+        let file = FileOffer::new(1, 5, [0u8; 32], "sub/notes.txt".to_string());
+        let nested = DirOffer::new(2, "tree".to_string(), vec![DirEntry::file(0, file)]);
+        let files = create_directory(&nested, &target).unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(tree.join("sub").is_dir());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
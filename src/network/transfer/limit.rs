@@ -0,0 +1,243 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+/// Upload rate limits in bytes per second, shared with the threads sending files. The global
+/// limit applies to all outgoing file data together and a transfer limit to a single file, so
+/// a file is sent at the lower of the two
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    inner: Arc<Mutex<Limits>>,
+}
+
+#[derive(Debug, Default)]
+struct Limits {
+    global: Bucket,
+    transfers: HashMap<u32, Bucket>,
+}
+
+/// Rate of a limit and when the data sent under it so far has been paid for
+#[derive(Debug, Default)]
+struct Bucket {
+    rate: Option<u64>,
+    free_at: Option<Instant>,
+}
+
+impl Bucket {
+    /// Reserves the time `bytes` take at the rate of the limit, returns when they can be sent
+    fn reserve(&mut self, bytes: u64, now: Instant) -> Instant {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return now,
+        };
+        // Time the limit was unused is not saved up, so an idle transfer does not burst
+        let start = self.free_at.map_or(now, |free_at| free_at.max(now));
+        self.free_at = Some(start + Duration::from_secs_f64(bytes as f64 / rate as f64));
+        start
+    }
+}
+
+impl RateLimits {
+    pub fn new(global: Option<u64>) -> Self {
+        let limits = Self::default();
+        limits.set_global(global);
+        limits
+    }
+
+    /// Limits all outgoing file data together, `None` removes the limit
+    pub fn set_global(&self, rate: Option<u64>) {
+        if let Ok(mut limits) = self.inner.lock() {
+            limits.global = Bucket {
+                rate,
+                free_at: None,
+            };
+        }
+    }
+
+    /// Limits the file data of a single transfer, `None` removes the limit
+    pub fn set_transfer(&self, id: u32, rate: Option<u64>) {
+        if let Ok(mut limits) = self.inner.lock() {
+            match rate {
+                Some(rate) => {
+                    limits.transfers.insert(
+                        id,
+                        Bucket {
+                            rate: Some(rate),
+                            free_at: None,
+                        },
+                    );
+                }
+                None => {
+                    limits.transfers.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// Rate the transfer is limited to by the global and its own limit
+    pub fn limit(&self, id: u32) -> Option<u64> {
+        let limits = self.inner.lock().ok()?;
+        let transfer = limits.transfers.get(&id).and_then(|bucket| bucket.rate);
+        match (limits.global.rate, transfer) {
+            (Some(global), Some(transfer)) => Some(global.min(transfer)),
+            (global, transfer) => global.or(transfer),
+        }
+    }
+
+    /// Forgets the limit of a transfer which has ended
+    pub fn remove(&self, id: u32) {
+        if let Ok(mut limits) = self.inner.lock() {
+            limits.transfers.remove(&id);
+        }
+    }
+
+    /// Blocks until `bytes` of the transfer can be sent without going over its limits
+    pub fn throttle(&self, id: u32, bytes: u64) {
+        let send_at = match self.inner.lock() {
+            Ok(mut limits) => {
+                let now = Instant::now();
+                let global = limits.global.reserve(bytes, now);
+                let transfer = limits
+                    .transfers
+                    .get_mut(&id)
+                    .map_or(now, |bucket| bucket.reserve(bytes, now));
+                global.max(transfer)
+            }
+            Err(_) => return,
+        };
+        let wait = send_at.saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Parses a rate in bytes per second like `500K`, `1.5MiB` or `2m`, with binary units.
+/// `off`, `none` and `0` mean no limit
+pub fn parse_rate(rate: &str) -> Result<Option<u64>> {
+    let rate = rate.trim();
+    if ["off", "none", "0"].contains(&rate.to_lowercase().as_str()) {
+        return Ok(None);
+    }
+
+    let lowercase = rate.to_lowercase();
+    let size = lowercase.strip_suffix("/s").unwrap_or(&lowercase);
+    parse_size(size)
+        .map(Some)
+        .map_err(|_| anyhow!("{} is not a valid rate, use a size like 500K or 2M", rate))
+}
+
+/// Parses a size in bytes like `64K`, `1.5MiB` or `2m`, with binary units
+pub fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let invalid = || anyhow!("{} is not a valid size, use a size like 64K or 16M", size);
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let unit = unit.trim().to_lowercase();
+    let unit = unit
+        .strip_suffix("ib")
+        .or_else(|| unit.strip_suffix('b'))
+        .unwrap_or(&unit);
+    let multiplier: u64 = match unit {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+
+    let bytes = (number * multiplier as f64).round();
+    if bytes >= 1.0 && bytes < u64::MAX as f64 {
+        Ok(bytes as u64)
+    } else {
+        Err(invalid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{parse_rate, parse_size, Bucket, RateLimits};
+
+    #[test]
+    fn parses_sizes_with_binary_units() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64K").unwrap(), 64 << 10);
+        assert_eq!(parse_size(" 16m ").unwrap(), 16 << 20);
+        assert_eq!(parse_size("1.5MiB").unwrap(), 3 << 19);
+        assert_eq!(parse_size("2 GB").unwrap(), 2 << 30);
+        assert_eq!(parse_size("1kb").unwrap(), 1 << 10);
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for size in ["", "K", "-1K", "0", "0.1", "12T", "1.2.3M", "ten", "1e9"] {
+            assert!(parse_size(size).is_err(), "{size:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("500K").unwrap(), Some(500 << 10));
+        assert_eq!(parse_rate("2M/s").unwrap(), Some(2 << 20));
+        for rate in ["off", "OFF", "none", "0"] {
+            assert_eq!(parse_rate(rate).unwrap(), None);
+        }
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn reserves_time_at_the_rate_of_the_bucket() {
+        let now = Instant::now();
+        let mut unlimited = Bucket::default();
+        assert_eq!(unlimited.reserve(1 << 20, now), now);
+
+        let mut bucket = Bucket {
+            rate: Some(1000),
+            free_at: None,
+        };
+        assert_eq!(bucket.reserve(500, now), now);
+        assert_eq!(bucket.reserve(500, now), now + Duration::from_millis(500));
+        assert_eq!(bucket.reserve(500, now), now + Duration::from_secs(1));
+
+        // Time the bucket was unused is not saved up
+        let later = now + Duration::from_secs(10);
+        assert_eq!(bucket.reserve(500, later), later);
+    }
+
+    #[test]
+    fn limits_transfers_to_the_lower_rate() {
+        let limits = RateLimits::new(Some(1000));
+        assert_eq!(limits.limit(1), Some(1000));
+        limits.set_transfer(1, Some(500));
+        assert_eq!(limits.limit(1), Some(500));
+        limits.set_transfer(1, Some(5000));
+        assert_eq!(limits.limit(1), Some(1000));
+        limits.set_global(None);
+        assert_eq!(limits.limit(1), Some(5000));
+        limits.remove(1);
+        assert_eq!(limits.limit(1), None);
+    }
+
+    #[test]
+    fn throttles_to_the_limit() {
+        let limits = RateLimits::new(Some(10_000));
+        let started = Instant::now();
+        for _ in 0..3 {
+            limits.throttle(1, 1000);
+        }
+        // The first chunk is sent right away, the others each wait a tenth of a second
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "took {elapsed:?}");
+        assert!(elapsed < Duration::from_secs(2), "took {elapsed:?}");
+    }
+}
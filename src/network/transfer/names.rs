@@ -0,0 +1,264 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+
+/// Longest file name, in bytes, most file systems can store
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Names which refer to devices instead of files on Windows, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Suffixes of the files `papercups` keeps next to the files being received
+const RESERVED_SUFFIXES: [&str; 2] = [".part", ".part.state"];
+
+/// Turns a file name sent by the peer into a single path component which is safe to create in
+/// the download directory.
+///
+/// Leading directories are stripped, so `dir/file.txt` becomes `file.txt`, but names which try
+/// to climb out of the directory with `..`, contain control characters or name a device are
+/// rejected.
+pub fn sanitize_file_name(name: &str) -> Result<String> {
+    let components: Vec<&str> = name.split(['/', '\\']).collect();
+    if components.contains(&"..") {
+        return Err(anyhow!("File name {:?} refers to a parent directory", name));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(anyhow!("File name {:?} contains control characters", name));
+    }
+    if name.contains(':') {
+        return Err(anyhow!(
+            "File name {:?} contains a drive or stream separator",
+            name
+        ));
+    }
+
+    // Windows silently drops trailing dots and spaces, which would turn `CON.` into `CON`
+    let file_name = components
+        .last()
+        .copied()
+        .unwrap_or_default()
+        .trim_end_matches(['.', ' '])
+        .trim_start();
+    if file_name.is_empty() {
+        return Err(anyhow!("File name {:?} is empty", name));
+    }
+    if file_name.len() > MAX_NAME_LENGTH {
+        return Err(anyhow!("File name {:?} is too long", name));
+    }
+
+    let stem = file_name.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        return Err(anyhow!("File name {:?} is reserved for a device", name));
+    }
+    if RESERVED_SUFFIXES
+        .iter()
+        .any(|suffix| file_name.ends_with(suffix))
+    {
+        return Err(anyhow!(
+            "File name {:?} is reserved for partial files",
+            name
+        ));
+    }
+
+    Ok(file_name.to_string())
+}
+
+/// Turns the `/` separated path of an entry in a directory sent by the peer into a relative
+/// path which stays inside the directory. Unlike names of files, nothing is stripped: the path
+/// is rejected if any of its components is not already a safe file name
+pub fn sanitize_relative_path(path: &str) -> Result<PathBuf> {
+    let mut relative_path = PathBuf::new();
+    for component in path.split('/') {
+        match sanitize_file_name(component) {
+            Ok(name) if name == component => relative_path.push(name),
+            Ok(_) => {
+                return Err(anyhow!(
+                    "Path {:?} has an invalid component {:?}",
+                    path,
+                    component
+                ))
+            }
+            Err(err) => return Err(err.context(format!("Path {path:?} is not valid"))),
+        }
+    }
+    Ok(relative_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{sanitize_file_name, sanitize_relative_path};
+
+    #[test]
+    fn keeps_ordinary_names() {
+        for name in [
+            "report.pdf",
+            "archive.tar.gz",
+            "résumé.txt",
+            ".bashrc",
+            "a b c",
+        ] {
+            assert_eq!(sanitize_file_name(name).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn strips_leading_directories() {
+        assert_eq!(sanitize_file_name("dir/file.txt").unwrap(), "file.txt");
+        assert_eq!(sanitize_file_name("/etc/passwd").unwrap(), "passwd");
+        assert_eq!(sanitize_file_name("dir\\file.txt").unwrap(), "file.txt");
+        assert_eq!(
+            sanitize_file_name("\\\\server\\share\\file.txt").unwrap(),
+            "file.txt"
+        );
+        assert_eq!(sanitize_file_name("./file.txt").unwrap(), "file.txt");
+    }
+
+    #[test]
+    fn rejects_parent_directories() {
+        for name in [
+            "..",
+            "../.bashrc",
+            "../../.bashrc",
+            "dir/../../etc/passwd",
+            "..\\..\\Windows\\System32\\drivers\\etc\\hosts",
+            "dir/..",
+        ] {
+            assert!(sanitize_file_name(name).is_err(), "{name:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_empty_names() {
+        for name in ["", ".", "/", "dir/", "   ", "...", ". ."] {
+            assert!(sanitize_file_name(name).is_err(), "{name:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        for name in [
+            "a\0b",
+            "a\nb",
+            "file\r.txt",
+            "\x1b[31mred",
+            "tab\there",
+            "del\x7f",
+        ] {
+            assert!(sanitize_file_name(name).is_err(), "{name:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_names() {
+        for name in [
+            "CON",
+            "con",
+            "Con.txt",
+            "NUL",
+            "nul.tar.gz",
+            "AUX",
+            "PRN",
+            "COM1",
+            "lpt9.log",
+            "CON.",
+            "CON ",
+            "CON .txt",
+        ] {
+            assert!(sanitize_file_name(name).is_err(), "{name:?} was accepted");
+        }
+        assert_eq!(sanitize_file_name("CONSOLE.txt").unwrap(), "CONSOLE.txt");
+        assert_eq!(sanitize_file_name("COM10").unwrap(), "COM10");
+    }
+
+    #[test]
+    fn rejects_drive_and_stream_separators() {
+        for name in [
+            "C:",
+            "C:evil.exe",
+            "file.txt:stream",
+            "C:\\Windows\\win.ini",
+        ] {
+            assert!(sanitize_file_name(name).is_err(), "{name:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn names_the_rejected_file_in_errors() {
+        let err = sanitize_file_name("../.bashrc").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "File name \"../.bashrc\" refers to a parent directory"
+        );
+        let err = sanitize_file_name("CON.txt").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "File name \"CON.txt\" is reserved for a device"
+        );
+    }
+
+    #[test]
+    fn rejects_names_of_partial_files() {
+        for name in ["movie.mkv.part", "movie.mkv.part.state"] {
+            assert!(sanitize_file_name(name).is_err(), "{name:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn trims_trailing_dots_and_spaces() {
+        assert_eq!(sanitize_file_name("file.txt. . ").unwrap(), "file.txt");
+        assert_eq!(sanitize_file_name("  file.txt").unwrap(), "file.txt");
+    }
+
+    #[test]
+    fn rejects_long_names() {
+        assert!(sanitize_file_name(&"a".repeat(256)).is_err());
+        assert!(sanitize_file_name(&"é".repeat(128)).is_err());
+        assert_eq!(sanitize_file_name(&"a".repeat(255)).unwrap().len(), 255);
+    }
+
+    #[test]
+    fn keeps_relative_paths() {
+        assert_eq!(
+            sanitize_relative_path("src/network/mod.rs").unwrap(),
+            PathBuf::from("src").join("network").join("mod.rs")
+        );
+        assert_eq!(
+            sanitize_relative_path(".git").unwrap(),
+            PathBuf::from(".git")
+        );
+    }
+
+    #[test]
+    fn rejects_escaping_relative_paths() {
+        for path in [
+            "",
+            "/etc/passwd",
+            "../outside",
+            "dir/../../outside",
+            "dir//file",
+            "dir/",
+            "dir\\..\\..\\outside",
+            "C:/Windows",
+            "dir/CON",
+            "dir/file. ",
+        ] {
+            assert!(
+                sanitize_relative_path(path).is_err(),
+                "{path:?} was accepted"
+            );
+        }
+        let err = sanitize_relative_path("docs/../x").unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Path \"docs/../x\" is not valid: File name \"..\" refers to a parent directory"
+        );
+    }
+}
@@ -1,8 +1,10 @@
 mod dialog_box;
+mod transfer_list;
 
-pub use dialog_box::{DialogBox, DialogBoxType, DialogCallback, DialogState};
+pub use dialog_box::{DialogBox, DialogBoxType, DialogCallback, DialogState, InputCallback};
+pub use transfer_list::TransferList;
 
-use std::net::TcpStream;
+use std::time::Duration;
 
 use tui::{
     layout::{Constraint, Direction, Layout, Rect},
@@ -11,9 +13,12 @@ use tui::{
     widgets::{Block, Borders, List, ListItem, Paragraph},
 };
 
-use crate::app::MsgType;
+use crate::{
+    app::{MsgType, Verification},
+    network::client::Client,
+};
 
-pub fn message_box(messages: &Vec<(MsgType, String)>) -> List {
+pub fn message_box(messages: &[(MsgType, String)]) -> List<'_> {
     let message_listitem: Vec<ListItem> = messages
         .iter()
         .map(|(a, m)| -> ListItem {
@@ -27,7 +32,7 @@ pub fn message_box(messages: &Vec<(MsgType, String)>) -> List {
     List::new(message_listitem).block(Block::default().borders(Borders::ALL).title("Messages"))
 }
 
-pub fn input_box(input: &str) -> Paragraph {
+pub fn input_box(input: &str) -> Paragraph<'_> {
     Paragraph::new(input)
         .style(Style::default().fg(Color::Yellow))
         .block(
@@ -37,13 +42,44 @@ pub fn input_box(input: &str) -> Paragraph {
         )
 }
 
-pub fn connection_status_message(client: &Option<TcpStream>) -> Paragraph {
+pub fn connection_status_message(
+    client: &Option<Client>,
+    verification: &Verification,
+    latency: Option<Duration>,
+) -> Paragraph<'static> {
     let span = if let Some(c) = client {
-        let ip = c.peer_addr().unwrap().ip();
-        Spans::from(vec![Span::styled(
-            format!("Connected to {}", ip),
-            Style::default().fg(Color::Green),
-        )])
+        let ip = c.peer_addr().ip();
+        let (status, color) = match verification {
+            Verification::Verified => ("verified".to_string(), Color::Green),
+            Verification::Unverified(sas) => (
+                format!(
+                    "not verified, compare {} with the peer and use ?verify",
+                    sas
+                ),
+                Color::Yellow,
+            ),
+            Verification::Pending => (
+                "waiting for the peer to connect back".to_string(),
+                Color::Yellow,
+            ),
+            Verification::Mismatch => (
+                "the peer used different keys, it may be impersonated".to_string(),
+                Color::Red,
+            ),
+            Verification::Unencrypted => ("not encrypted".to_string(), Color::Red),
+        };
+        let mut spans = vec![
+            Span::styled(
+                format!("Connected to {}, ", ip),
+                Style::default().fg(Color::Green),
+            ),
+            Span::styled(status, Style::default().fg(color)),
+        ];
+        // Only peers which answer pings have a latency
+        if let Some(latency) = latency {
+            spans.push(Span::raw(format!(" ({} ms)", latency.as_millis())));
+        }
+        Spans::from(spans)
     } else {
         let red_style = Style::default().fg(Color::Red);
         Spans::from(vec![
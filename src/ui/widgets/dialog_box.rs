@@ -14,11 +14,16 @@ use crate::App;
 
 pub type DialogCallback = Box<dyn Fn(&mut App) -> Result<()>>;
 
+/// Callback of an input dialog, called with the text entered by the user
+pub type InputCallback = Box<dyn Fn(&mut App, &str) -> Result<()>>;
+
 /// DialogBoxState is associate type used for stateful render of Dialogbox
 pub struct DialogState {
     is_yes: bool,
+    input: String,
     pub yes_fn: DialogCallback,
     pub no_fn: DialogCallback,
+    pub input_fn: Option<InputCallback>,
 }
 
 impl DialogState {
@@ -30,39 +35,64 @@ impl DialogState {
         self.is_yes = !self.is_yes
     }
 
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.input.push(c)
+    }
+
+    pub fn pop(&mut self) {
+        self.input.pop();
+    }
+
+    /// Whether the decision can be switched to an input dialog, like saving a file under
+    /// another path
+    pub fn has_input(&self) -> bool {
+        self.input_fn.is_some()
+    }
+
     pub fn new(yes_fn: DialogCallback, no_fn: DialogCallback) -> Self {
         Self {
             is_yes: false,
+            input: String::new(),
             yes_fn,
             no_fn,
+            input_fn: None,
         }
     }
+
+    /// Lets the user enter a text, starting with `input`, instead of deciding
+    pub fn with_input(mut self, input: String, input_fn: InputCallback) -> Self {
+        self.input = input;
+        self.input_fn = Some(input_fn);
+        self
+    }
 }
 
 impl Debug for DialogState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DialogState")
             .field("is_yes", &self.is_yes)
+            .field("input", &self.input)
             .finish()
     }
 }
 
 impl Default for DialogState {
     fn default() -> Self {
-        Self {
-            is_yes: false,
-            yes_fn: Box::new(|_| Ok(())),
-            no_fn: Box::new(|_| Ok(())),
-        }
+        Self::new(Box::new(|_| Ok(())), Box::new(|_| Ok(())))
     }
 }
 
-/// Enum indicates whether the DialogBox is for showing information or for making
-/// decisions
+/// Enum indicates whether the DialogBox is for showing information, for making
+/// decisions or for entering a text
 #[derive(Debug, Clone, Copy)]
 pub enum DialogBoxType {
     Info,
     Decision,
+    Input,
 }
 
 /// Custom widget that opens a popup to get user input
@@ -130,6 +160,15 @@ impl StatefulWidget for DialogBox {
                 .alignment(Alignment::Center);
                 negative_msg.render(negative_input_area, buf);
 
+                if state.has_input() {
+                    let input_msg = Paragraph::new(vec![Spans::from(vec![
+                        Span::styled("S", Style::default().add_modifier(Modifier::UNDERLINED)),
+                        Span::raw("ave as"),
+                    ])])
+                    .alignment(Alignment::Center);
+                    input_msg.render(input_areas[0], buf);
+                }
+
                 let highlight_style = Style::default().fg(Color::Black);
                 if state.is_yes {
                     buf.set_style(positive_input_area, highlight_style.bg(Color::LightGreen));
@@ -152,6 +191,32 @@ impl StatefulWidget for DialogBox {
 
                 buf.set_style(input_areas[1], Style::default().fg(Color::Black));
             }
+            DialogBoxType::Input => {
+                let input_areas = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(80), Constraint::Percentage(20)])
+                    .split(splitted_area[1]);
+                let text_area = input_areas[0];
+
+                // Only the end of a text too long for the dialog is shown, as that is where
+                // the user types
+                let width = text_area.width.saturating_sub(1) as usize;
+                let skipped = state.input.chars().count().saturating_sub(width);
+                let text: String = state.input.chars().skip(skipped).collect();
+                let input = Paragraph::new(Spans::from(vec![
+                    Span::raw(text),
+                    Span::styled(" ", Style::default().add_modifier(Modifier::REVERSED)),
+                ]))
+                .style(Style::default().bg(Color::DarkGray));
+                input.render(text_area, buf);
+
+                let msg = Paragraph::new(vec![Spans::from(vec![Span::styled(
+                    "Save",
+                    Style::default().fg(Color::Black).bg(Color::LightGreen),
+                )])])
+                .alignment(Alignment::Center);
+                msg.render(input_areas[1], buf);
+            }
         }
     }
 }
@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use tui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Color, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Gauge, Paragraph, Widget},
+};
+
+use crate::network::transfer::{TransferDirection, TransferProgress};
+
+/// Custom widget that shows the active file transfers as gauges
+#[derive(Debug)]
+pub struct TransferList<'a> {
+    transfers: &'a [TransferProgress],
+}
+
+impl<'a> TransferList<'a> {
+    pub fn new(transfers: &'a [TransferProgress]) -> Self {
+        Self { transfers }
+    }
+}
+
+impl Widget for TransferList<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let block = Block::default().borders(Borders::ALL).title("Transfers");
+        let render_area = block.inner(area);
+        block.render(area, buf);
+
+        // Every transfer takes two lines, one for its name and one for its gauge
+        for (i, transfer) in self.transfers.iter().enumerate() {
+            let y = render_area.y + 2 * i as u16;
+            if y + 2 > render_area.bottom() {
+                break;
+            }
+
+            let (arrow, color) = match transfer.direction() {
+                TransferDirection::Send => ("↑", Color::Cyan),
+                TransferDirection::Receive => ("↓", Color::Green),
+            };
+            let name = Paragraph::new(Spans::from(vec![
+                Span::styled(arrow, Style::default().fg(color)),
+                Span::raw(format!(" {}", transfer.name())),
+                Span::styled(
+                    format!(" {:08x}", transfer.id()),
+                    Style::default().fg(Color::DarkGray),
+                ),
+            ]));
+            name.render(Rect::new(render_area.x, y, render_area.width, 1), buf);
+
+            let limit = transfer.limit().map_or(String::new(), |limit| {
+                format!(" (max {}/s)", format_bytes(limit))
+            });
+            let label = format!(
+                "{}/{} {}/s{} ETA {}",
+                format_bytes(transfer.done()),
+                format_bytes(transfer.total()),
+                format_bytes(transfer.rate() as u64),
+                limit,
+                transfer.eta().map_or("--".to_string(), format_duration)
+            );
+            let gauge = Gauge::default()
+                .gauge_style(Style::default().fg(color).bg(Color::Black))
+                .ratio(transfer.ratio().clamp(0.0, 1.0))
+                .label(label);
+            gauge.render(Rect::new(render_area.x, y + 1, render_area.width, 1), buf);
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}